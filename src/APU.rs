@@ -0,0 +1,1038 @@
+use crate::Resampler::Resampler;
+
+pub const SOUND_REGISTERS_START: usize = 0xFF10;
+pub const SOUND_REGISTERS_END: usize = 0xFF2F;
+pub const SOUND_REGISTERS_SIZE: usize = SOUND_REGISTERS_END - SOUND_REGISTERS_START + 1;
+
+pub const WAVE_RAM_START: usize = 0xFF30;
+pub const WAVE_RAM_END: usize = 0xFF3F;
+pub const WAVE_RAM_SIZE: usize = WAVE_RAM_END - WAVE_RAM_START + 1;
+
+//Each channel has five registers, NRx0-NRx4, even where NRx0 doesn't do anything
+pub const NR10: usize = 0xFF10;
+pub const NR20: usize = 0xFF15;
+pub const NR30: usize = 0xFF1A;
+pub const NR40: usize = 0xFF1F;
+pub const NR50: usize = 0xFF24;
+pub const NR51: usize = 0xFF25;
+pub const NR52: usize = 0xFF26;
+
+//Bits that always read back as 1, either unused or write only. 0xFF15, 0xFF1F and 0xFF27-0xFF2F aren't registers at all.
+const READ_MASKS: [u8; SOUND_REGISTERS_SIZE] =
+[
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, //NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, //NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, //NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, //NR40-NR44
+    0x00, 0x00, 0x70,             //NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+//Waveforms for the four NRx1 duty settings: 12.5%, 25%, 50% and 75%
+const DUTY_PATTERNS: [[u8; 8]; 4] =
+[
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+//Noise channel period in T-cycles for each NR43 divisor code, before the shift
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+//The top frequency value a sweep can reach before it switches channel 1 off
+const MAX_FREQUENCY: u16 = 2047;
+
+//Digital 0-15 out of a channel to the -1.0 to 1.0 a DAC puts out. A DAC that's off contributes nothing.
+fn dac(digital: u8, dac_enabled: bool) -> f32
+{
+    if dac_enabled
+    {
+        digital as f32 / 7.5 - 1.0
+    }
+    else
+    {
+        0.0
+    }
+}
+
+//Switches a channel off once it's played for long enough, 64 steps (256 on the wave channel) of the 256 Hz clock
+#[derive(Clone, Copy)]
+struct LengthCounter
+{
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+impl LengthCounter
+{
+    fn new(max: u16) -> LengthCounter
+    {
+        LengthCounter { counter: 0, max, enabled: false }
+    }
+    fn load(&mut self, length: u8)
+    {
+        self.counter = self.max - length as u16;
+    }
+    //Returns true when the channel should be switched off
+    fn clock(&mut self) -> bool
+    {
+        if self.enabled && self.counter > 0
+        {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+    //NRx4 writes. If the frame sequencer just clocked length (extra_clock), turning length on or triggering
+    //with an empty counter clocks it one more time. Returns true when the channel should be switched off.
+    fn write_control(&mut self, enabled: bool, trigger: bool, extra_clock: bool) -> bool
+    {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+        let mut expired = false;
+        if extra_clock && !was_enabled && enabled && self.counter > 0
+        {
+            self.counter -= 1;
+            expired = self.counter == 0 && !trigger;
+        }
+        if trigger && self.counter == 0
+        {
+            self.counter = self.max;
+            if enabled && extra_clock
+            {
+                self.counter -= 1;
+            }
+        }
+        expired
+    }
+}
+
+//Volume ramp from NRx2. The direction and pace are latched when the channel is triggered.
+#[derive(Clone, Copy)]
+struct Envelope
+{
+    volume: u8,
+    increase: bool,
+    pace: u8,
+    timer: u8,
+}
+impl Envelope
+{
+    fn new() -> Envelope
+    {
+        Envelope { volume: 0, increase: false, pace: 0, timer: 0 }
+    }
+    fn trigger(&mut self, register: u8)
+    {
+        self.volume = register >> 4;
+        self.increase = (register & 0x08) != 0;
+        self.pace = register & 0x07;
+        self.timer = self.pace;
+    }
+    //64 Hz, a pace of 0 leaves the volume where it is
+    fn clock(&mut self)
+    {
+        if self.pace == 0
+        {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0
+        {
+            self.timer = self.pace;
+            if self.increase && self.volume < 15
+            {
+                self.volume += 1;
+            }
+            else if !self.increase && self.volume > 0
+            {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+//Channel 1's frequency sweep, working on a shadow copy of the frequency taken on trigger
+#[derive(Clone, Copy)]
+struct Sweep
+{
+    enabled: bool,
+    timer: u8,
+    shadow: u16,
+    //Set once a calculation has subtracted, clearing the negate bit after that kills the channel
+    negated: bool,
+}
+impl Sweep
+{
+    fn new() -> Sweep
+    {
+        Sweep { enabled: false, timer: 0, shadow: 0, negated: false }
+    }
+}
+
+//Channels 1 and 2, a square wave with a choice of duty cycle. Only channel 1 gets a sweep.
+#[derive(Clone)]
+struct Pulse
+{
+    registers: [u8; 5],
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+    //T-cycles until the next duty step
+    timer: u32,
+    duty_position: usize,
+}
+impl Pulse
+{
+    fn new(sweep: bool) -> Pulse
+    {
+        Pulse
+        {
+            registers: [0; 5],
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if sweep {Some(Sweep::new())} else {None},
+            timer: 8192,
+            duty_position: 0,
+        }
+    }
+    fn frequency(&self) -> u16
+    {
+        ((self.registers[4] as u16 & 0x07) << 8) | self.registers[3] as u16
+    }
+    fn set_frequency(&mut self, frequency: u16)
+    {
+        self.registers[3] = frequency as u8;
+        self.registers[4] = (self.registers[4] & 0xF8) | ((frequency >> 8) as u8 & 0x07);
+    }
+    fn period(&self) -> u32
+    {
+        (2048 - self.frequency() as u32) * 4
+    }
+    fn dac_enabled(&self) -> bool
+    {
+        (self.registers[2] & 0xF8) != 0
+    }
+    fn write(&mut self, register: usize, value: u8, extra_length_clock: bool)
+    {
+        let old = self.registers[register];
+        self.registers[register] = value;
+        match register
+        {
+            0 =>
+            {
+                if let Some(sweep) = self.sweep
+                {
+                    if sweep.negated && (old & 0x08) != 0 && (value & 0x08) == 0
+                    {
+                        self.enabled = false;
+                    }
+                }
+            },
+            1 => self.length.load(value & 0x3F),
+            2 if !self.dac_enabled() => self.enabled = false,
+            4 =>
+            {
+                let trigger = (value & 0x80) != 0;
+                if self.length.write_control((value & 0x40) != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger
+                {
+                    self.trigger();
+                }
+            },
+            _ => {},
+        }
+    }
+    fn trigger(&mut self)
+    {
+        self.enabled = self.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger(self.registers[2]);
+        let frequency = self.frequency();
+        if let Some(sweep) = &mut self.sweep
+        {
+            let pace = (self.registers[0] >> 4) & 0x07;
+            let shift = self.registers[0] & 0x07;
+            sweep.shadow = frequency;
+            sweep.timer = if pace == 0 {8} else {pace};
+            sweep.enabled = pace != 0 || shift != 0;
+            sweep.negated = false;
+            if shift != 0 && self.sweep_calculate() > MAX_FREQUENCY
+            {
+                self.enabled = false;
+            }
+        }
+    }
+    fn sweep_calculate(&mut self) -> u16
+    {
+        let shift = self.registers[0] & 0x07;
+        let negate = (self.registers[0] & 0x08) != 0;
+        match &mut self.sweep
+        {
+            Some(sweep) =>
+            {
+                let delta = sweep.shadow >> shift;
+                if negate
+                {
+                    sweep.negated = true;
+                    sweep.shadow - delta
+                }
+                else
+                {
+                    sweep.shadow + delta
+                }
+            },
+            None => 0,
+        }
+    }
+    //128 Hz
+    fn clock_sweep(&mut self)
+    {
+        let pace = (self.registers[0] >> 4) & 0x07;
+        let shift = self.registers[0] & 0x07;
+        let Some(sweep) = &mut self.sweep else { return };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0
+        {
+            return;
+        }
+        sweep.timer = if pace == 0 {8} else {pace};
+        if !sweep.enabled || pace == 0
+        {
+            return;
+        }
+        let frequency = self.sweep_calculate();
+        if frequency > MAX_FREQUENCY
+        {
+            self.enabled = false;
+        }
+        else if shift != 0
+        {
+            if let Some(sweep) = &mut self.sweep
+            {
+                sweep.shadow = frequency;
+            }
+            self.set_frequency(frequency);
+            //The new value is checked again straight away, without being stored
+            if self.sweep_calculate() > MAX_FREQUENCY
+            {
+                self.enabled = false;
+            }
+        }
+    }
+    fn tick(&mut self, mut cycles: u32)
+    {
+        while cycles >= self.timer
+        {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+    fn output(&self) -> f32
+    {
+        let duty = (self.registers[1] >> 6) as usize;
+        let high = DUTY_PATTERNS[duty][self.duty_position];
+        let digital = if self.enabled {high * self.envelope.volume} else {0};
+        dac(digital, self.dac_enabled())
+    }
+}
+
+//Channel 3, plays back the 32 4-bit samples in wave RAM
+#[derive(Clone)]
+struct Wave
+{
+    registers: [u8; 5],
+    ram: [u8; WAVE_RAM_SIZE],
+    enabled: bool,
+    length: LengthCounter,
+    timer: u32,
+    position: usize,
+    //The sample being played, only updated when the position moves on
+    sample: u8,
+}
+impl Wave
+{
+    fn new() -> Wave
+    {
+        Wave
+        {
+            registers: [0; 5],
+            ram: [0; WAVE_RAM_SIZE],
+            enabled: false,
+            length: LengthCounter::new(256),
+            timer: 4096,
+            position: 0,
+            sample: 0,
+        }
+    }
+    fn frequency(&self) -> u16
+    {
+        ((self.registers[4] as u16 & 0x07) << 8) | self.registers[3] as u16
+    }
+    fn period(&self) -> u32
+    {
+        (2048 - self.frequency() as u32) * 2
+    }
+    fn dac_enabled(&self) -> bool
+    {
+        (self.registers[0] & 0x80) != 0
+    }
+    fn write(&mut self, register: usize, value: u8, extra_length_clock: bool)
+    {
+        self.registers[register] = value;
+        match register
+        {
+            0 if !self.dac_enabled() => self.enabled = false,
+            1 => self.length.load(value),
+            4 =>
+            {
+                let trigger = (value & 0x80) != 0;
+                if self.length.write_control((value & 0x40) != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger
+                {
+                    self.enabled = self.dac_enabled();
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            },
+            _ => {},
+        }
+    }
+    fn tick(&mut self, mut cycles: u32)
+    {
+        while cycles >= self.timer
+        {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            let byte = self.ram[self.position / 2];
+            self.sample = if self.position.is_multiple_of(2) {byte >> 4} else {byte & 0x0F};
+        }
+        self.timer -= cycles;
+    }
+    fn output(&self) -> f32
+    {
+        //NR32 output level: mute, 100%, 50% or 25%
+        let shift = match (self.registers[2] >> 5) & 0x03
+        {
+            0 => 4,
+            level => level - 1,
+        };
+        let digital = if self.enabled {self.sample >> shift} else {0};
+        dac(digital, self.dac_enabled())
+    }
+}
+
+//Channel 4, pseudo random noise out of a linear feedback shift register
+#[derive(Clone)]
+struct Noise
+{
+    registers: [u8; 5],
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    timer: u32,
+    lfsr: u16,
+}
+impl Noise
+{
+    fn new() -> Noise
+    {
+        Noise
+        {
+            registers: [0; 5],
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            timer: 8,
+            lfsr: 0,
+        }
+    }
+    fn period(&self) -> u32
+    {
+        NOISE_DIVISORS[(self.registers[3] & 0x07) as usize] << (self.registers[3] >> 4)
+    }
+    fn dac_enabled(&self) -> bool
+    {
+        (self.registers[2] & 0xF8) != 0
+    }
+    fn write(&mut self, register: usize, value: u8, extra_length_clock: bool)
+    {
+        self.registers[register] = value;
+        match register
+        {
+            1 => self.length.load(value & 0x3F),
+            2 if !self.dac_enabled() => self.enabled = false,
+            4 =>
+            {
+                let trigger = (value & 0x80) != 0;
+                if self.length.write_control((value & 0x40) != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger
+                {
+                    self.enabled = self.dac_enabled();
+                    self.timer = self.period();
+                    self.envelope.trigger(self.registers[2]);
+                    self.lfsr = 0x7FFF;
+                }
+            },
+            _ => {},
+        }
+    }
+    fn tick(&mut self, mut cycles: u32)
+    {
+        while cycles >= self.timer
+        {
+            cycles -= self.timer;
+            self.timer = self.period();
+            //Shifts of 14 and 15 never clock the LFSR
+            if (self.registers[3] >> 4) < 14
+            {
+                let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+                self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+                //7 bit mode also feeds back into bit 6, giving a much shorter, more tonal sequence
+                if (self.registers[3] & 0x08) != 0
+                {
+                    self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+                }
+            }
+        }
+        self.timer -= cycles;
+    }
+    fn output(&self) -> f32
+    {
+        let high = (!self.lfsr & 1) as u8;
+        let digital = if self.enabled {high * self.envelope.volume} else {0};
+        dac(digital, self.dac_enabled())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Channel
+{
+    Pulse1,
+    Pulse2,
+    Wave,
+    Noise,
+}
+impl Channel
+{
+    pub const ALL: [Channel; 4] = [Channel::Pulse1, Channel::Pulse2, Channel::Wave, Channel::Noise];
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Wave => "wave",
+            Channel::Noise => "noise",
+        }
+    }
+}
+
+//How often the oscilloscope takes a sample, in M-cycles
+const SCOPE_INTERVAL: u32 = 16;
+
+//Everything the host has asked of the APU rather than anything the hardware has: resampling, stems, muting
+//and the oscilloscope. It belongs to the emulator, so it's carried across power cycles.
+#[derive(Default)]
+pub struct HostOutput
+{
+    //Only there once the host has asked for audio at some sample rate
+    resampler: Option<Resampler>,
+    //With stems on, each channel also gets resampled on its own, in Channel order
+    stems: bool,
+    stem_resamplers: Vec<Resampler>,
+    //With any channel soloed only the soloed ones are heard in the mix, otherwise everything that isn't muted.
+    //Stems always get the whole channel.
+    muted: [bool; 4],
+    soloed: [bool; 4],
+    //Each channel's DAC output every SCOPE_INTERVAL M-cycles, muted or not, while the scope is on
+    scope: Option<Vec<[f32; 4]>>,
+    scope_counter: u32,
+}
+
+pub struct APU
+{
+    powered: bool,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    wave: Wave,
+    noise: Noise,
+    //NR50: master volume for each side (VIN is never used by cartridges so it's ignored), NR51: which channels go to which side
+    master_volume: u8,
+    panning: u8,
+    //512 Hz frame sequencer, clocked by DIV bit 4 falling. frame_step is the next of its 8 steps to run.
+    frame_step: u8,
+    divider_bit: bool,
+    host: HostOutput,
+}
+impl APU
+{
+    pub fn new() -> APU
+    {
+        APU
+        {
+            powered: false,
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            master_volume: 0,
+            panning: 0,
+            frame_step: 0,
+            divider_bit: false,
+            host: HostOutput::default(),
+        }
+    }
+    pub fn take_host_output(&mut self) -> HostOutput
+    {
+        std::mem::take(&mut self.host)
+    }
+    pub fn set_host_output(&mut self, host: HostOutput)
+    {
+        self.host = host;
+    }
+    pub fn set_sample_rate(&mut self, rate: Option<u32>)
+    {
+        self.host.resampler = rate.map(Resampler::new);
+        self.rebuild_stems();
+    }
+    pub fn set_stems(&mut self, stems: bool)
+    {
+        self.host.stems = stems;
+        self.rebuild_stems();
+    }
+    pub fn stems(&self) -> bool
+    {
+        self.host.stems
+    }
+    fn rebuild_stems(&mut self)
+    {
+        self.host.stem_resamplers = match (self.host.stems, self.sample_rate())
+        {
+            (true, Some(rate)) => Channel::ALL.iter().map(|_| Resampler::new(rate)).collect(),
+            _ => Vec::new(),
+        };
+    }
+    pub fn sample_rate(&self) -> Option<u32>
+    {
+        self.host.resampler.as_ref().map(|resampler| resampler.output_rate())
+    }
+    pub fn resampler_mut(&mut self) -> Option<&mut Resampler>
+    {
+        self.host.resampler.as_mut()
+    }
+    pub fn stem_resampler_mut(&mut self, channel: Channel) -> Option<&mut Resampler>
+    {
+        self.host.stem_resamplers.get_mut(channel as usize)
+    }
+    pub fn set_muted(&mut self, channel: Channel, muted: bool)
+    {
+        self.host.muted[channel as usize] = muted;
+    }
+    pub fn muted(&self, channel: Channel) -> bool
+    {
+        self.host.muted[channel as usize]
+    }
+    pub fn set_soloed(&mut self, channel: Channel, soloed: bool)
+    {
+        self.host.soloed[channel as usize] = soloed;
+    }
+    pub fn soloed(&self, channel: Channel) -> bool
+    {
+        self.host.soloed[channel as usize]
+    }
+    //Whether a channel makes it into the mix after muting and soloing
+    pub fn audible(&self, channel: Channel) -> bool
+    {
+        if self.host.soloed.contains(&true)
+        {
+            self.host.soloed[channel as usize]
+        }
+        else
+        {
+            !self.host.muted[channel as usize]
+        }
+    }
+    pub fn set_scope(&mut self, enabled: bool)
+    {
+        self.host.scope = enabled.then(Vec::new);
+    }
+    //Oscilloscope samples since the last call, each channel's DAC output in Channel order
+    pub fn take_scope_samples(&mut self) -> Vec<[f32; 4]>
+    {
+        self.host.scope.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+    //NR10-NR52 as written, including the write only bits reads hide
+    pub fn registers(&self) -> [u8; NR52 - NR10 + 1]
+    {
+        std::array::from_fn(|index| self.register(NR10 + index))
+    }
+    //Advance by the cycles the CPU just spent. divider is DIV after the timer has been stepped.
+    pub fn step(&mut self, cycles: u8, divider: u8)
+    {
+        let divider_bit = (divider & 0x10) != 0;
+        let frame_clock = self.divider_bit && !divider_bit;
+        self.divider_bit = divider_bit;
+        for _ in 0..cycles / 4
+        {
+            if self.powered
+            {
+                self.pulse1.tick(4);
+                self.pulse2.tick(4);
+                self.wave.tick(4);
+                self.noise.tick(4);
+            }
+            if self.host.resampler.is_some()
+            {
+                let channels = self.channel_outputs();
+                for (resampler, (left, right)) in self.host.stem_resamplers.iter_mut().zip(channels)
+                {
+                    resampler.push(left, right);
+                }
+                let (left, right) = self.mix(channels);
+                if let Some(resampler) = &mut self.host.resampler
+                {
+                    resampler.push(left, right);
+                }
+            }
+            if self.host.scope.is_some()
+            {
+                self.host.scope_counter += 1;
+                if self.host.scope_counter == SCOPE_INTERVAL
+                {
+                    self.host.scope_counter = 0;
+                    let dacs = self.dac_outputs();
+                    if let Some(scope) = &mut self.host.scope
+                    {
+                        scope.push(dacs);
+                    }
+                }
+            }
+        }
+        if self.powered && frame_clock
+        {
+            self.clock_frame_sequencer();
+        }
+    }
+    //Length on every even step (256 Hz), sweep on 2 and 6 (128 Hz), envelopes on 7 (64 Hz)
+    fn clock_frame_sequencer(&mut self)
+    {
+        if self.frame_step.is_multiple_of(2)
+        {
+            if self.pulse1.length.clock()
+            {
+                self.pulse1.enabled = false;
+            }
+            if self.pulse2.length.clock()
+            {
+                self.pulse2.enabled = false;
+            }
+            if self.wave.length.clock()
+            {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock()
+            {
+                self.noise.enabled = false;
+            }
+        }
+        if self.frame_step == 2 || self.frame_step == 6
+        {
+            self.pulse1.clock_sweep();
+        }
+        if self.frame_step == 7
+        {
+            self.pulse1.envelope.clock();
+            self.pulse2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+    //Left and right output, each -1.0 to 1.0. Every channel goes through its own DAC, then NR51 picks which
+    //side it's mixed into and NR50 scales each side. Muted channels are left out.
+    pub fn output(&self) -> (f32, f32)
+    {
+        self.mix(self.channel_outputs())
+    }
+    fn mix(&self, channels: [(f32, f32); 4]) -> (f32, f32)
+    {
+        Channel::ALL.into_iter().zip(channels)
+            .filter(|&(channel, _)| self.audible(channel))
+            .fold((0.0, 0.0), |(left, right), (_, output)| (left + output.0, right + output.1))
+    }
+    //Each channel straight out of its DAC, before panning, volume or muting
+    pub fn dac_outputs(&self) -> [f32; 4]
+    {
+        if !self.powered
+        {
+            return [0.0; 4];
+        }
+        [self.pulse1.output(), self.pulse2.output(), self.wave.output(), self.noise.output()]
+    }
+    //What each channel adds to the left and right output, in Channel order, whether it's muted or not
+    pub fn channel_outputs(&self) -> [(f32, f32); 4]
+    {
+        let left_volume = (((self.master_volume >> 4) & 0x07) + 1) as f32 / 8.0 / 4.0;
+        let right_volume = ((self.master_volume & 0x07) + 1) as f32 / 8.0 / 4.0;
+        let dacs = self.dac_outputs();
+        let mut outputs = [(0.0, 0.0); 4];
+        for (channel, output) in dacs.iter().enumerate()
+        {
+            if (self.panning & (0x10 << channel)) != 0
+            {
+                outputs[channel].0 = output * left_volume;
+            }
+            if (self.panning & (0x01 << channel)) != 0
+            {
+                outputs[channel].1 = output * right_volume;
+            }
+        }
+        outputs
+    }
+    fn status(&self) -> u8
+    {
+        (self.powered as u8) << 7 |
+        (self.noise.enabled as u8) << 3 |
+        (self.wave.enabled as u8) << 2 |
+        (self.pulse2.enabled as u8) << 1 |
+        (self.pulse1.enabled as u8)
+    }
+    fn register(&self, address: usize) -> u8
+    {
+        match address
+        {
+            NR10..=0xFF14 => self.pulse1.registers[address - NR10],
+            NR20..=0xFF19 => self.pulse2.registers[address - NR20],
+            NR30..=0xFF1E => self.wave.registers[address - NR30],
+            NR40..=0xFF23 => self.noise.registers[address - NR40],
+            NR50 => self.master_volume,
+            NR51 => self.panning,
+            NR52 => self.status(),
+            _ => 0xFF,
+        }
+    }
+    pub fn read(&self, address: usize) -> u8
+    {
+        match address
+        {
+            SOUND_REGISTERS_START..=SOUND_REGISTERS_END => self.register(address) | READ_MASKS[address - SOUND_REGISTERS_START],
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave.ram[address - WAVE_RAM_START],
+            _ => 0xFF,
+        }
+    }
+    pub fn write(&mut self, address: usize, value: u8)
+    {
+        //Wave RAM and the power switch always work. While the power's off the only other thing that can be
+        //written on the DMG is the length counters.
+        match address
+        {
+            NR52 => self.set_power((value & 0x80) != 0),
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave.ram[address - WAVE_RAM_START] = value,
+            _ if !self.powered => self.write_length(address, value),
+            _ => self.write_register(address, value),
+        }
+    }
+    fn write_register(&mut self, address: usize, value: u8)
+    {
+        //Whether the frame sequencer just did a length step, which changes what NRx4 writes do
+        let extra_length_clock = self.frame_step % 2 == 1;
+        match address
+        {
+            NR10..=0xFF14 => self.pulse1.write(address - NR10, value, extra_length_clock),
+            NR20..=0xFF19 => self.pulse2.write(address - NR20, value, extra_length_clock),
+            NR30..=0xFF1E => self.wave.write(address - NR30, value, extra_length_clock),
+            NR40..=0xFF23 => self.noise.write(address - NR40, value, extra_length_clock),
+            NR50 => self.master_volume = value,
+            NR51 => self.panning = value,
+            _ => {},
+        }
+    }
+    fn write_length(&mut self, address: usize, value: u8)
+    {
+        match address
+        {
+            0xFF11 => self.pulse1.length.load(value & 0x3F),
+            0xFF16 => self.pulse2.length.load(value & 0x3F),
+            0xFF1B => self.wave.length.load(value),
+            0xFF20 => self.noise.length.load(value & 0x3F),
+            _ => {},
+        }
+    }
+    //Powering off clears every register and stops all the channels, everything but wave RAM and the
+    //length counters goes back to how it was at power on
+    fn set_power(&mut self, powered: bool)
+    {
+        if powered == self.powered
+        {
+            return;
+        }
+        if !powered
+        {
+            let mut apu = APU::new();
+            apu.wave.ram = self.wave.ram;
+            apu.pulse1.length.counter = self.pulse1.length.counter;
+            apu.pulse2.length.counter = self.pulse2.length.counter;
+            apu.wave.length.counter = self.wave.length.counter;
+            apu.noise.length.counter = self.noise.length.counter;
+            apu.divider_bit = self.divider_bit;
+            apu.host = self.take_host_output();
+            *self = apu;
+        }
+        self.powered = powered;
+        self.frame_step = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    //Each falling edge of DIV bit 4 runs one frame sequencer step
+    fn clock_frame_sequencer(apu: &mut APU, steps: usize)
+    {
+        for _ in 0..steps
+        {
+            apu.step(4, 0x10);
+            apu.step(4, 0x00);
+        }
+    }
+
+    #[test]
+    fn length_counter_switches_channel_off()
+    {
+        let mut apu = APU::new();
+        apu.write(NR52, 0x80);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 62);
+        apu.write(0xFF14, 0xC0);
+        assert_eq!(apu.read(NR52) & 0x01, 1);
+        //Length is clocked on every other step
+        clock_frame_sequencer(&mut apu, 2);
+        assert_eq!(apu.read(NR52) & 0x01, 1);
+        clock_frame_sequencer(&mut apu, 1);
+        assert_eq!(apu.read(NR52) & 0x01, 0);
+    }
+
+    #[test]
+    fn length_counter_ignored_unless_enabled()
+    {
+        let mut apu = APU::new();
+        apu.write(NR52, 0x80);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 63);
+        apu.write(0xFF14, 0x80);
+        clock_frame_sequencer(&mut apu, 8);
+        assert_eq!(apu.read(NR52) & 0x01, 1);
+    }
+
+    #[test]
+    fn sweep_overflow_on_trigger()
+    {
+        let mut apu = APU::new();
+        apu.write(NR52, 0x80);
+        apu.write(NR10, 0x11);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0xFF);
+        apu.write(0xFF14, 0x87);
+        assert_eq!(apu.read(NR52) & 0x01, 0);
+    }
+
+    #[test]
+    fn sweep_updates_frequency_then_overflows()
+    {
+        let mut apu = APU::new();
+        apu.write(NR52, 0x80);
+        apu.write(NR10, 0x11);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x85);
+        assert_eq!(apu.read(NR52) & 0x01, 1);
+        //Sweep runs on step 2: 0x500 + 0x280 goes in, then the check of the next one overflows
+        clock_frame_sequencer(&mut apu, 3);
+        assert_eq!(apu.pulse1.frequency(), 0x780);
+        assert_eq!(apu.read(NR52) & 0x01, 0);
+    }
+
+    #[test]
+    fn clearing_negate_after_subtracting_kills_channel()
+    {
+        let mut apu = APU::new();
+        apu.write(NR52, 0x80);
+        apu.write(NR10, 0x19);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF14, 0x84);
+        assert_eq!(apu.read(NR52) & 0x01, 1);
+        apu.write(NR10, 0x11);
+        assert_eq!(apu.read(NR52) & 0x01, 0);
+    }
+
+    #[test]
+    fn lfsr_periods()
+    {
+        //How many clocks the LFSR takes to get back to where a trigger leaves it, in 15 and 7 bit modes
+        for (nr43, mask, expected) in [(0x00, 0x7FFF, 0x7FFF), (0x08, 0x7F, 0x7F)]
+        {
+            let mut noise = Noise::new();
+            noise.write(2, 0xF0, false);
+            noise.write(3, nr43, false);
+            noise.write(4, 0x80, false);
+            let start = noise.lfsr & mask;
+            let period = noise.period();
+            let clocks = (1..=0x8000).find(|_|
+            {
+                noise.tick(period);
+                noise.lfsr & mask == start
+            });
+            assert_eq!(clocks, Some(expected));
+        }
+    }
+
+    #[test]
+    fn power_off_clears_registers_but_not_wave_ram()
+    {
+        let mut apu = APU::new();
+        apu.write(NR52, 0x80);
+        apu.write(NR50, 0x77);
+        apu.write(WAVE_RAM_START, 0x12);
+        apu.write(NR52, 0x00);
+        assert_eq!(apu.read(NR50), 0x00);
+        assert_eq!(apu.read(NR52), 0x70);
+        assert_eq!(apu.read(WAVE_RAM_START), 0x12);
+        //Registers can't be written until it's back on
+        apu.write(NR50, 0x77);
+        assert_eq!(apu.read(NR50), 0x00);
+        apu.write(NR52, 0x80);
+        apu.write(NR50, 0x77);
+        assert_eq!(apu.read(NR50), 0x77);
+    }
+
+    #[test]
+    fn stems_ignore_muting()
+    {
+        let mut apu = APU::new();
+        apu.write(NR52, 0x80);
+        apu.write(NR50, 0x77);
+        apu.write(NR51, 0x11);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF14, 0x80);
+        apu.set_muted(Channel::Pulse1, true);
+        assert_eq!(apu.output(), (0.0, 0.0));
+        assert_ne!(apu.channel_outputs()[0], (0.0, 0.0));
+    }
+}
@@ -0,0 +1,95 @@
+//Without the audio feature open always fails, so nothing else in here gets used
+#![cfg_attr(not(feature = "audio"), allow(dead_code))]
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use GB_Emulator::GameBoy;
+
+//How much audio to keep queued for the device, enough to ride out a late video frame without being heard as lag
+const LATENCY_MS: usize = 50;
+//A queue this many times the target gets cut back, after the window's been dragged around or the emulator stalled
+const MAX_QUEUE_FACTOR: usize = 4;
+
+type Queue = Arc<Mutex<VecDeque<[f32; 2]>>>;
+
+//Plays the emulator's audio through the host's default output device. The device pulls frames off a queue from
+//its own thread, and dynamic rate control keeps the emulator filling it at the rate it drains.
+pub struct AudioSink
+{
+    #[cfg(feature = "audio")]
+    _stream: cpal::Stream,
+    queue: Queue,
+    sample_rate: u32,
+    //Frames to keep queued
+    target: usize,
+}
+impl AudioSink
+{
+    #[cfg(feature = "audio")]
+    pub fn open() -> Result<AudioSink, String>
+    {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+        let device = cpal::default_host().default_output_device().ok_or("no output device")?;
+        let default = device.default_output_config().map_err(|error| error.to_string())?;
+        let sample_rate = default.sample_rate();
+        //The callback only deals in f32, which nearly every device offers at its default rate
+        let config = if default.sample_format() == cpal::SampleFormat::F32
+        {
+            default
+        }
+        else
+        {
+            device.supported_output_configs().map_err(|error| error.to_string())?
+                .find(|range| range.sample_format() == cpal::SampleFormat::F32 && range.min_sample_rate() <= sample_rate && sample_rate <= range.max_sample_rate())
+                .map(|range| range.with_sample_rate(sample_rate))
+                .ok_or("output device doesn't take f32 samples")?
+        };
+        let channels = config.channels() as usize;
+        let target = sample_rate.0 as usize * LATENCY_MS / 1000;
+        //Start with the queue at its target so rate control isn't playing catch up from empty
+        let queue: Queue = Arc::new(Mutex::new(VecDeque::from(vec![[0.0; 2]; target])));
+        let device_queue = queue.clone();
+        let stream = device.build_output_stream(&config.config(), move |data: &mut [f32], _: &cpal::OutputCallbackInfo|
+        {
+            let mut queue = device_queue.lock().unwrap();
+            for frame in data.chunks_mut(channels)
+            {
+                //Silence if the emulator hasn't kept up
+                let [left, right] = queue.pop_front().unwrap_or([0.0; 2]);
+                match frame
+                {
+                    [mono] => *mono = (left + right) / 2.0,
+                    [first, second, rest @ ..] =>
+                    {
+                        *first = left;
+                        *second = right;
+                        rest.fill(0.0);
+                    },
+                    [] => {},
+                }
+            }
+        }, |error| eprintln!("Audio output error: {}", error), None).map_err(|error| error.to_string())?;
+        stream.play().map_err(|error| error.to_string())?;
+        Ok(AudioSink { _stream: stream, queue, sample_rate: sample_rate.0, target })
+    }
+    #[cfg(not(feature = "audio"))]
+    pub fn open() -> Result<AudioSink, String>
+    {
+        Err("built without the audio feature, rebuild with --features audio".to_string())
+    }
+    pub fn sample_rate(&self) -> u32
+    {
+        self.sample_rate
+    }
+    //Queue a video frame's worth of audio and steer the emulator's output rate by how full the queue is
+    pub fn play(&mut self, gameboy: &mut GameBoy, frames: &[[f32; 2]])
+    {
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(frames);
+        let excess = queue.len().saturating_sub(self.target * MAX_QUEUE_FACTOR);
+        queue.drain(..excess);
+        gameboy.adjust_audio_rate(queue.len(), self.target);
+    }
+}
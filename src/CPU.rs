@@ -1,16 +1,19 @@
+use crate::Cartridge::Cartridge;
+use crate::InterruptFlags::Interrupt;
+use crate::Model::Model;
 use crate::Memory;
 use Memory::MemoryBus;
 
 //Defines register structure
 pub struct Registers {
-    a: u8,
-    b: u8,
-    c: u8,
-    d: u8,
-    e: u8,
-    f: FlagsRegister,
-    h: u8,
-    l: u8,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: FlagsRegister,
+    pub h: u8,
+    pub l: u8,
 }
 
 //Implements functionality for the registers structure
@@ -23,7 +26,7 @@ impl Registers {
 
     pub fn get_bc(&mut self) -> u16
     {
-        return ((self.b as u16) << 8 | self.c as u16);
+        (self.b as u16) << 8 | self.c as u16
     }
 
     pub fn set_af(&mut self, value: u16)
@@ -35,7 +38,7 @@ impl Registers {
     pub fn get_af(&mut self) -> u16
     {
         let f_by: u8 = (&self.f).into();
-        return ((self.a as u16) << 8 | f_by as u16);
+        (self.a as u16) << 8 | f_by as u16
     }
 
     pub fn set_de(&mut self, value: u16)
@@ -46,7 +49,7 @@ impl Registers {
 
     pub fn get_de(&mut self) -> u16
     {
-        return ((self.d as u16) << 8 | self.e as u16);
+        (self.d as u16) << 8 | self.e as u16
     }
 
     pub fn set_hl(&mut self, value: u16)
@@ -57,7 +60,7 @@ impl Registers {
 
     pub fn get_hl(&mut self) -> u16
     {
-        return ((self.h as u16) << 8 | self.l as u16);
+        (self.h as u16) << 8 | self.l as u16
     }
 } 
 //provides a default for register values
@@ -84,10 +87,10 @@ const CARRY_FLAG_BYTE_POSITION: u8 = 4;
 //Defines structure for the flag register
 pub struct FlagsRegister
     {
-        zero: bool,
-        subtract: bool,
-        half_carry: bool,
-        carry: bool,
+        pub zero: bool,
+        pub subtract: bool,
+        pub half_carry: bool,
+        pub carry: bool,
     }
 //implements standard conversion for flagregister to u8 and vice versa
 impl std::convert::From<FlagsRegister> for u8  
@@ -128,10 +131,10 @@ impl std::convert::From<&FlagsRegister> for u8 {
 
 pub struct CPU
 {
-    registers: Registers,
-    pc: u16,
-    sp: u16,
-    bus: MemoryBus,
+    pub registers: Registers,
+    pub pc: u16,
+    pub sp: u16,
+    pub bus: MemoryBus,
     is_halted: bool,
     ime: bool,
     ime_scheduled: bool,
@@ -139,6 +142,7 @@ pub struct CPU
 }
 
 
+#[derive(Clone, Copy)]
 enum Instruction
 {
     ADD(ArithmeticTarget),
@@ -190,30 +194,37 @@ enum Instruction
     DI(),
     RETI()
 }
+#[derive(Clone, Copy)]
 enum ArithmeticTarget
 {
     A, B, C, D, E, H, L, HL, U8
 }
+#[derive(Clone, Copy)]
 enum RstTargets
 {
     OOH, OBH, IOH, IBH, ZOH, ZBH, EOH, EBH 
 }
+#[derive(Clone, Copy)]
 enum ArithmeticTarget16
 {
     HL, BC, DE, AF, SP
 }
+#[derive(Clone, Copy)]
 enum LoadByteTarget 
 {
     A, B, C, D, E, H, L, HLI
 }
+#[derive(Clone, Copy)]
 enum LoadByteSource 
 {
     A, B, C, D, E, H, L, D8, HLI
 }
+#[derive(Clone, Copy)]
 enum LoadByteIndirect
 {
     BC, DE, HLP, HLN
 }
+#[derive(Clone, Copy)]
 enum LoadType 
 {
   Byte(LoadByteTarget, LoadByteSource),
@@ -223,18 +234,22 @@ enum LoadType
   AFromByteAddress(LoadByteAddress),
   ByteAddressFromA(LoadByteAddress)
 }
+#[derive(Clone, Copy)]
 enum LoadWordTarget
 {
-    AF, HL, DE, BC, HLI, SP, I16
+    HL, DE, BC, SP, I16
 }
+#[derive(Clone, Copy)]
 enum LoadWordSource
 {
-    AF, BC, DE, HL, D16, HLI, SP, SP8
+    HL, D16, SP, SP8
 }
+#[derive(Clone, Copy)]
 enum LoadByteAddress
 {
     U8, C, U16
 }
+#[derive(Clone, Copy)]
 enum JumpTest
 {
     NotZero, Zero, NotCarry, Carry, Always
@@ -774,23 +789,102 @@ impl Instruction
                     0xFF => Some(Instruction::SET(ArithmeticTarget::A, 7)),
                 }
         }
+
+    //How many T-cycles the instruction takes. Conditional JP/JR/CALL/RET take longer when the branch is taken.
+    fn cycles(&self, branch_taken: bool) -> u8
+        {
+            match self
+                {
+                    Instruction::ADD(target) | Instruction::ADC(target) | Instruction::SUB(target) | Instruction::SBC(target)
+                    | Instruction::AND(target) | Instruction::OR(target) | Instruction::XOR(target) | Instruction::CP(target) =>
+                        match target
+                        {
+                            ArithmeticTarget::HL | ArithmeticTarget::U8 => 8,
+                            _ => 4,
+                        },
+                    Instruction::INC8(target) | Instruction::DEC8(target) =>
+                        match target
+                        {
+                            ArithmeticTarget::HL => 12,
+                            _ => 4,
+                        },
+                    Instruction::ADDHL(_) | Instruction::INC16(_) | Instruction::DEC16(_) => 8,
+                    Instruction::CCF() | Instruction::SCF() | Instruction::RRA() | Instruction::RLA() | Instruction::RRCA()
+                    | Instruction::RLCA() | Instruction::CPL() | Instruction::DAA() | Instruction::NOP() | Instruction::HALT()
+                    | Instruction::STOP() | Instruction::EI() | Instruction::DI() | Instruction::JPHL() => 4,
+                    Instruction::BIT(target, _) =>
+                        match target
+                        {
+                            ArithmeticTarget::HL => 12,
+                            _ => 8,
+                        },
+                    Instruction::RES(target, _) | Instruction::SET(target, _) | Instruction::SRL(target) | Instruction::RR(target)
+                    | Instruction::RL(target) | Instruction::RRC(target) | Instruction::RLC(target) | Instruction::SRA(target)
+                    | Instruction::SLA(target) | Instruction::SWAP(target) =>
+                        match target
+                        {
+                            ArithmeticTarget::HL => 16,
+                            _ => 8,
+                        },
+                    Instruction::JP(_) => if branch_taken {16} else {12},
+                    Instruction::JR(_) => if branch_taken {12} else {8},
+                    Instruction::CALL(_) => if branch_taken {24} else {12},
+                    Instruction::RET(JumpTest::Always) => 16,
+                    Instruction::RET(_) => if branch_taken {20} else {8},
+                    Instruction::RETI() | Instruction::RST(_) | Instruction::PUSH(_) | Instruction::ADDSP() => 16,
+                    Instruction::POP(_) => 12,
+                    Instruction::LD(load_type) =>
+                        match load_type
+                        {
+                            LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::D8) => 12,
+                            LoadType::Byte(LoadByteTarget::HLI, _) | LoadType::Byte(_, LoadByteSource::D8) | LoadType::Byte(_, LoadByteSource::HLI) => 8,
+                            LoadType::Byte(_, _) => 4,
+                            LoadType::Word(LoadWordTarget::I16, _) => 20,
+                            LoadType::Word(_, LoadWordSource::D16) | LoadType::Word(_, LoadWordSource::SP8) => 12,
+                            LoadType::Word(_, _) => 8,
+                            LoadType::AFromIndirect(_) | LoadType::IndirectFromA(_) => 8,
+                            LoadType::AFromByteAddress(address) | LoadType::ByteAddressFromA(address) =>
+                                match address
+                                {
+                                    LoadByteAddress::C => 8,
+                                    LoadByteAddress::U8 => 12,
+                                    LoadByteAddress::U16 => 16,
+                                },
+                        },
+                }
+        }
 }
 
 impl CPU
 {
-    pub fn new(boot_rom: Vec<u8>, game_rom:Vec<u8>) -> CPU
+    //With no boot ROM everything is set up as if the model's boot ROM had just handed over to the cartridge
+    pub fn new(boot_rom: Option<Vec<u8>>, cartridge: Cartridge, model: Model) -> CPU
         {
-            CPU 
+            let skip_boot_rom = boot_rom.is_none();
+            let mut cpu = CPU 
             { 
                 registers: Registers::default(),
                 pc: 0x0000,
                 sp: 0x0000,
-                bus: MemoryBus::new(boot_rom, game_rom),
+                bus: MemoryBus::new(boot_rom, cartridge),
                 is_halted: false, 
                 ime: false,
                 ime_scheduled: false,
                 stopped: false
+            };
+            if skip_boot_rom
+            {
+                cpu.skip_boot_rom(model);
             }
+            cpu
+        }
+    fn skip_boot_rom(&mut self, model: Model)
+        {
+            let [a, f, b, c, d, e, h, l] = model.registers(self.bus.cartridge.header.header_checksum);
+            self.registers = Registers { a, b, c, d, e, f: FlagsRegister::from(f), h, l };
+            self.sp = 0xFFFE;
+            self.pc = 0x0100;
+            self.bus.skip_boot_rom(model);
         }
     fn read_next_byte(&mut self) -> u8
         {
@@ -799,69 +893,83 @@ impl CPU
     fn read_next_word(&mut self) -> u16
         {
             let lo = self.bus.read_byte(self.pc + 1) as u16;
-            let hi = (self.bus.read_byte(self.pc + 2) << 8) as u16;
-            let word = lo | hi;
-            word
+            let hi = (self.bus.read_byte(self.pc + 2) as u16) << 8;
+            lo | hi
         }
-    fn step(&mut self)
+    //Runs one instruction (or one idle M-cycle while halted), services interrupts and clocks the bus.
+    //Returns how many T-cycles passed.
+    pub fn step(&mut self) -> u8
         {
-            let mut instruction_byte = self.bus.read_byte(self.pc);
-            let prefixed = instruction_byte == 0xCB;
-            if prefixed {
-              instruction_byte = self.bus.read_byte(self.pc + 1);
+            let mut cycles = if self.is_halted
+            {
+                4
             }
-        
-            let next_pc = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) {
-              self.execute(instruction)
-            } else {
-              let description = format!("0x{}{:x}", if prefixed { "cb" } else { "" }, instruction_byte);
-              panic!("Unkown instruction found for: {}", description)
+            else
+            {
+                let mut instruction_byte = self.bus.read_byte(self.pc);
+                let prefixed = instruction_byte == 0xCB;
+                if prefixed {
+                  instruction_byte = self.bus.read_byte(self.pc + 1);
+                }
+
+                let (next_pc, cycles) = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) {
+                  self.execute(instruction)
+                } else {
+                  let description = format!("0x{}{:x}", if prefixed { "cb" } else { "" }, instruction_byte);
+                  panic!("Unkown instruction found for: {}", description)
+                };
+                self.pc = next_pc;
+                cycles
             };
-            if self.ime
+            //HALT wakes up on any pending interrupt, even if IME is off and it won't be serviced
+            if self.is_halted && self.pending_interrupts() != 0
+            {
+                self.is_halted = false;
+            }
+            if self.ime && self.check_interrupt()
             {
-                self.check_interrupt();
+                cycles += 20;
             }
             self.after_instruction();
-            self.pc = next_pc;
+            self.bus.step(cycles);
+            cycles
           }
-    fn check_interrupt(&mut self)
+    fn pending_interrupts(&mut self) -> u8
     {
-        let pending_interrupts: u8 = self.bus.interrupt_flag.to_byte() & self.bus.interrupt_register.to_byte();
-        if pending_interrupts & 0x1 != 0
-        {
-            self.handle_interrupt(0x0040, 0);
-        }
-        else if pending_interrupts & 0x2 != 0 
-        {
-            self.handle_interrupt(0x0048, 1);
-        }
-        else if pending_interrupts & 0x4 != 0 
-        {
-            self.handle_interrupt(0x0050, 2);
-        }
-        else if pending_interrupts & 0x8 != 0 
-        {
-            self.handle_interrupt(0x0058, 3);
-        }
-        else if pending_interrupts & 0x10 != 0 
+        self.bus.interrupt_flag.to_byte() & self.bus.interrupt_register.to_byte() & 0x1F
+    }
+    //Dispatches the highest priority pending interrupt, if there is one
+    fn check_interrupt(&mut self) -> bool
+    {
+        let pending_interrupts: u8 = self.pending_interrupts();
+        match Interrupt::ALL.into_iter().find(|interrupt| pending_interrupts & interrupt.bit() != 0)
         {
-            self.handle_interrupt(0x0060, 4);
+            Some(interrupt) =>
+            {
+                self.handle_interrupt(interrupt);
+                true
+            },
+            None => false,
         }
     }
-    fn handle_interrupt(&mut self, address: u16, bit: u8)
+    fn handle_interrupt(&mut self, interrupt: Interrupt)
     {
         self.ime = false;
-        let new_interrupt = self.bus.interrupt_flag.to_byte() & !(1 << bit);
-        self.bus.interrupt_flag.from_byte(new_interrupt);
+        self.bus.interrupt_flag.acknowledge(interrupt);
         self.sp = self.sp.wrapping_sub(2);
         self.bus.write_word(self.sp, self.pc);
-        self.pc = address;
+        self.pc = interrupt.vector();
     }
-    fn execute(&mut self, instruction: Instruction) -> u16
+    //Returns the address of the next instruction and how many T-cycles this one took
+    fn execute(&mut self, instruction: Instruction) -> (u16, u8)
         {
-            if self.is_halted {self.pc}
-            else{
-            match instruction
+            //Branches never touch the flags, so the condition can be settled before running them
+            let branch_taken = match instruction
+            {
+                Instruction::JP(test) | Instruction::JR(test) | Instruction::CALL(test) | Instruction::RET(test) => self.jump_test(test),
+                _ => false,
+            };
+            let next_pc = match instruction
             {
                 Instruction::ADD(target) => 
                 {
@@ -1251,17 +1359,9 @@ impl CPU
                     self.daa();
                     self.pc.wrapping_add(1)
                 }
-                Instruction::JP(test) => 
+                Instruction::JP(_) => 
                 {
-                    let jump_condition = match test 
-                    {
-                        JumpTest::NotZero => !self.registers.f.zero,
-                        JumpTest::NotCarry => !self.registers.f.carry,
-                        JumpTest::Zero => self.registers.f.zero,
-                        JumpTest::Carry => self.registers.f.carry,
-                        JumpTest::Always => true
-                    };
-                    self.jump(jump_condition)
+                    self.jump(branch_taken)
                 }
                 Instruction::LD(load_type) => 
                 {
@@ -1302,22 +1402,16 @@ impl CPU
                             {
                                 let source_value = match source
                                 {
-                                    LoadWordSource::AF => self.registers.get_af(),
-                                    LoadWordSource::BC => self.registers.get_bc(),
-                                    LoadWordSource::DE => self.registers.get_de(),
                                     LoadWordSource::HL => self.registers.get_hl(),
                                     LoadWordSource::D16 => self.read_next_word(),
-                                    LoadWordSource::HLI => self.bus.read_word(self.registers.get_hl()),
                                     LoadWordSource::SP => self.sp,
                                     LoadWordSource::SP8 => self.addsp(),
                                 };
                                 match target
                                 {
-                                    LoadWordTarget::AF => self.registers.set_af(source_value),
                                     LoadWordTarget::BC => self.registers.set_bc(source_value),
                                     LoadWordTarget::DE => self.registers.set_de(source_value),
                                     LoadWordTarget::HL => self.registers.set_hl(source_value),
-                                    LoadWordTarget::HLI => self.bus.write_word(self.registers.get_hl(), source_value),
                                     LoadWordTarget::SP => self.sp = source_value,
                                     LoadWordTarget::I16 => {let address = self.bus.read_word(self.pc.wrapping_add(1)); self.bus.write_word(address, source_value);},
                                 };
@@ -1425,41 +1519,17 @@ impl CPU
                        self.sp = self.addsp();
                        self.pc.wrapping_add(2)
                 }
-                Instruction::CALL(test) =>
+                Instruction::CALL(_) =>
                 {
-                    let jump_condition = match test
-                    {
-                        JumpTest::Always => true,
-                        JumpTest::Carry => self.registers.f.carry,
-                        JumpTest::NotCarry => !self.registers.f.carry,
-                        JumpTest::NotZero => !self.registers.f.zero,
-                        JumpTest::Zero => self.registers.f.zero,
-                    };
-                    self.call(jump_condition)
+                    self.call(branch_taken)
                 }
-                Instruction::RET(test) =>
+                Instruction::RET(_) =>
                 {
-                    let jump_condition = match test
-                    {
-                        JumpTest::Always => true,
-                        JumpTest::Carry => self.registers.f.carry,
-                        JumpTest::NotCarry => !self.registers.f.carry,
-                        JumpTest::NotZero => !self.registers.f.zero,
-                        JumpTest::Zero => self.registers.f.zero,
-                    };
-                    self.return_(jump_condition)
+                    self.return_(branch_taken)
                 }
-                Instruction::JR(test) =>
+                Instruction::JR(_) =>
                 {
-                let jump_condition = match test
-                    {
-                        JumpTest::Always => true,
-                        JumpTest::Carry => self.registers.f.carry,
-                        JumpTest::NotCarry => !self.registers.f.carry,
-                        JumpTest::NotZero => !self.registers.f.zero,
-                        JumpTest::Zero => self.registers.f.zero,
-                    };
-                    self.jr(jump_condition)
+                    self.jr(branch_taken)
                 }
                 Instruction::JPHL() =>
                 {
@@ -1467,6 +1537,8 @@ impl CPU
                 }
                 Instruction::RST(target) =>
                 {
+                    let return_address = self.pc.wrapping_add(1);
+                    self.push(return_address);
                     let add: u16 = match target
                     {
                         RstTargets::OOH => 0x0000,
@@ -1492,11 +1564,22 @@ impl CPU
                 }
                 Instruction::RETI() =>
                 {
-                    self.ei();
-                    self.return_(true);
-                    self.pc.wrapping_add(1)
+                    //Unlike EI there is no delay, interrupts are back on straight away
+                    self.ime = true;
+                    self.return_(true)
                 }
-            }
+            };
+            (next_pc, instruction.cycles(branch_taken))
+        }
+    fn jump_test(&self, test: JumpTest) -> bool
+        {
+            match test
+            {
+                JumpTest::NotZero => !self.registers.f.zero,
+                JumpTest::NotCarry => !self.registers.f.carry,
+                JumpTest::Zero => self.registers.f.zero,
+                JumpTest::Carry => self.registers.f.carry,
+                JumpTest::Always => true
             }
         }
     fn add(&mut self, value: u8) -> u8
@@ -1548,7 +1631,7 @@ impl CPU
         }
     fn and(&mut self, value: u8)
         {
-            self.registers.a = self.registers.a & value;
+            self.registers.a &= value;
             self.registers.f.zero = self.registers.a == 0; 
             self.registers.f.subtract = false; 
             self.registers.f.half_carry = true; 
@@ -1556,7 +1639,7 @@ impl CPU
         }
     fn or(&mut self, value: u8)
         {
-            self.registers.a = self.registers.a | value;
+            self.registers.a |= value;
             self.registers.f.zero = self.registers.a == 0; 
             self.registers.f.subtract = false; 
             self.registers.f.half_carry = true; 
@@ -1680,14 +1763,12 @@ impl CPU
     fn res(&mut self, bit: u8, value: u8) -> u8
         {
             let bit_num = !(0x01 << bit);
-            let new_value = value & bit_num;
-            new_value
+            value & bit_num
         }
     fn set(&mut self, bit: u8, value: u8) -> u8
         {
             let bit_num = 0x01 << bit;
-            let new_value = value | bit_num;
-            new_value
+            value | bit_num
         }
     fn srl(&mut self, value: u8) -> u8
         {
@@ -1757,7 +1838,7 @@ impl CPU
         }
     fn swap(&mut self, value: u8) -> u8
         {
-            let new_value = value >> 4 | value << 4;
+            let new_value = value.rotate_left(4);
             self.registers.f.zero = new_value == 0; 
             self.registers.f.subtract = false; 
             self.registers.f.half_carry = false;
@@ -1865,7 +1946,8 @@ impl CPU
             let offset = self.read_next_byte() as i8; // Fetch the signed 8-bit offset
             if should_jump
             {
-                let pc_before = self.pc as i32;
+                //The offset is relative to the instruction after the 2 byte JR
+                let pc_before = self.pc.wrapping_add(2) as i32;
                 let new_pc = pc_before.wrapping_add(offset as i32);
                 new_pc as u16
             }
@@ -1891,4 +1973,108 @@ impl CPU
                 self.ime_scheduled = false;
             }
         }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::Cartridge::test_rom;
+
+    #[test]
+    fn post_boot_state()
+    {
+        //A, B, C, D, E, H, L, the Z, N, H and C flags (the DMG's depend on the header checksum not being 0) and DIV
+        let expected =
+        [
+            (Model::DMG0, [0x01, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03], (false, false, false, false), 0x18),
+            (Model::DMG, [0x01, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D], (true, false, true, true), 0xAB),
+        ];
+        for (model, registers, flags, divider) in expected
+        {
+            let mut cpu = CPU::new(None, Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap(), model);
+            let r = &cpu.registers;
+            assert_eq!([r.a, r.b, r.c, r.d, r.e, r.h, r.l], registers, "{:?}", model);
+            assert_eq!((r.f.zero, r.f.subtract, r.f.half_carry, r.f.carry), flags, "{:?}", model);
+            assert_eq!((cpu.sp, cpu.pc), (0xFFFE, 0x0100));
+            assert!(!cpu.bus.boot_rom_enabled);
+            assert_eq!(cpu.bus.read_byte(0xFF04), divider, "{:?}", model);
+            //P1, SC, TAC, IF, NR50, NR51, NR52 with the boot chime still on channel 1, LCDC and BGP
+            for (address, value) in [(0xFF00, 0xCF), (0xFF02, 0x7E), (0xFF07, 0xF8), (0xFF0F, 0xE1), (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF26, 0xF1), (0xFF40, 0x91), (0xFF47, 0xFC)]
+            {
+                assert_eq!(cpu.bus.read_byte(address), value, "{:?} 0x{:04X}", model, address);
+            }
+        }
+    }
+
+    #[test]
+    fn branch_and_cb_cycles()
+    {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        //RST 0x08 lands on a RET, CALL C on RET NC then RET C
+        rom[0x0008] = 0xC9;
+        rom[0x0200..0x0202].copy_from_slice(&[0xD0, 0xD8]);
+        rom[0x0100..0x0120].copy_from_slice(&
+        [
+            0x20, 0x02,         //JR NZ (Z is set after boot)
+            0x28, 0x01,         //JR Z, +1
+            0x00,
+            0xD2, 0x00, 0x00,   //JP NC, 0x0000
+            0xDA, 0x0C, 0x01,   //JP C, 0x010C
+            0x00,
+            0xD4, 0x00, 0x00,   //CALL NC, 0x0000
+            0xDC, 0x00, 0x02,   //CALL C, 0x0200
+            0xCF,               //RST 0x08
+            0x21, 0x00, 0xC0,   //LD HL, 0xC000
+            0xCB, 0x46,         //BIT 0, (HL)
+            0xCB, 0x86,         //RES 0, (HL)
+            0xCB, 0x40,         //BIT 0, B
+            0xCB, 0x80,         //RES 0, B
+            0x18, 0xFE,         //JR -2
+        ]);
+        let mut cpu = CPU::new(None, Cartridge::from_bytes(rom).unwrap(), Model::DMG);
+        //Where each instruction leaves PC and how long it took
+        let expected =
+        [
+            (0x0102, 8), (0x0105, 12), (0x0108, 12), (0x010C, 16), (0x010F, 12), (0x0200, 24), (0x0201, 8), (0x0112, 20),
+            (0x0008, 16), (0x0113, 16), (0x0116, 12), (0x0118, 12), (0x011A, 16), (0x011C, 8), (0x011E, 8), (0x011E, 12),
+        ];
+        for (pc, cycles) in expected
+        {
+            let from = cpu.pc;
+            assert_eq!((cpu.step(), cpu.pc), (cycles, pc), "from 0x{:04X}", from);
+        }
+        //RST pushed the address after itself
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.bus.read_word(0xFFFC), 0x0113);
+    }
+
+    #[test]
+    fn halt_and_interrupt_dispatch_cycles()
+    {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        //EI, HALT, then NOPs. The timer handler is just RETI.
+        rom[0x0100..0x0102].copy_from_slice(&[0xFB, 0x76]);
+        rom[0x0050] = 0xD9;
+        let mut cpu = CPU::new(None, Cartridge::from_bytes(rom).unwrap(), Model::DMG);
+        cpu.bus.write_byte(0xFFFF, 0x04);
+        cpu.bus.write_byte(0xFF0F, 0x00);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.step(), 4);
+        for _ in 0..3
+        {
+            assert_eq!(cpu.step(), 4);
+            assert_eq!(cpu.pc, 0x0102);
+        }
+        //Waking up costs the idle M-cycle, then 20 more to push PC and jump
+        cpu.bus.interrupt_flag.request(Interrupt::Timer);
+        assert_eq!(cpu.step(), 24);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(cpu.bus.read_word(cpu.sp), 0x0102);
+        assert_eq!(cpu.step(), 16);
+        assert_eq!((cpu.pc, cpu.sp), (0x0102, 0xFFFE));
+        //RETI turned IME back on, so the next request is taken straight after a NOP
+        cpu.bus.interrupt_flag.request(Interrupt::Timer);
+        assert_eq!(cpu.step(), 24);
+        assert_eq!(cpu.pc, 0x0050);
+    }
+}
@@ -0,0 +1,488 @@
+use std::fmt;
+
+use crate::MBC::{MBC, MBC1, MBC2, MBC2_RAM_SIZE, MBC3, MBC5};
+use crate::RTC::RealTimeClock;
+
+pub const HEADER_START: usize = 0x0100;
+pub const HEADER_END: usize = 0x014F;
+
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+const NEW_LICENSEE_CODE: usize = 0x0144;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const OLD_LICENSEE_CODE: usize = 0x014B;
+const ROM_VERSION: usize = 0x014C;
+const HEADER_CHECKSUM: usize = 0x014D;
+const GLOBAL_CHECKSUM: usize = 0x014E;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+#[derive(Debug)]
+pub enum CartridgeError
+{
+    //Not even big enough to hold the header
+    TooSmall(usize),
+    UnknownCartridgeType(u8),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+    //The header promises more ROM than the image has
+    Truncated { expected: usize, actual: usize },
+    UnsupportedMBC(MBCKind),
+}
+impl fmt::Display for CartridgeError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            CartridgeError::TooSmall(size) => write!(f, "ROM is {} bytes, too small to contain a cartridge header", size),
+            CartridgeError::UnknownCartridgeType(byte) => write!(f, "unknown cartridge type 0x{:02X}", byte),
+            CartridgeError::UnknownRomSize(byte) => write!(f, "unknown ROM size code 0x{:02X}", byte),
+            CartridgeError::UnknownRamSize(byte) => write!(f, "unknown RAM size code 0x{:02X}", byte),
+            CartridgeError::Truncated { expected, actual } => write!(f, "header declares {} bytes of ROM but the image is {} bytes", expected, actual),
+            CartridgeError::UnsupportedMBC(kind) => write!(f, "{:?} cartridges are not supported", kind),
+        }
+    }
+}
+impl std::error::Error for CartridgeError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CGBSupport
+{
+    None,
+    //Uses CGB features but still runs on a DMG
+    Enhanced,
+    Only,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MBCKind
+{
+    RomOnly,
+    MBC1,
+    MBC2,
+    MMM01,
+    MBC3,
+    MBC5,
+    MBC6,
+    MBC7,
+    PocketCamera,
+    TAMA5,
+    HuC3,
+    HuC1,
+}
+//Byte 0x147, which controller the cartridge has plus whatever else is on the board
+#[derive(Clone, Copy, Debug)]
+pub struct CartridgeType
+{
+    pub code: u8,
+    pub mbc: MBCKind,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+impl std::convert::TryFrom<u8> for CartridgeType
+{
+    type Error = CartridgeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error>
+    {
+        //(controller, ram, battery, timer, rumble)
+        let (mbc, ram, battery, timer, rumble) = match value
+        {
+            0x00 => (MBCKind::RomOnly, false, false, false, false),
+            0x01 => (MBCKind::MBC1, false, false, false, false),
+            0x02 => (MBCKind::MBC1, true, false, false, false),
+            0x03 => (MBCKind::MBC1, true, true, false, false),
+            0x05 => (MBCKind::MBC2, false, false, false, false),
+            0x06 => (MBCKind::MBC2, false, true, false, false),
+            0x08 => (MBCKind::RomOnly, true, false, false, false),
+            0x09 => (MBCKind::RomOnly, true, true, false, false),
+            0x0B => (MBCKind::MMM01, false, false, false, false),
+            0x0C => (MBCKind::MMM01, true, false, false, false),
+            0x0D => (MBCKind::MMM01, true, true, false, false),
+            0x0F => (MBCKind::MBC3, false, true, true, false),
+            0x10 => (MBCKind::MBC3, true, true, true, false),
+            0x11 => (MBCKind::MBC3, false, false, false, false),
+            0x12 => (MBCKind::MBC3, true, false, false, false),
+            0x13 => (MBCKind::MBC3, true, true, false, false),
+            0x19 => (MBCKind::MBC5, false, false, false, false),
+            0x1A => (MBCKind::MBC5, true, false, false, false),
+            0x1B => (MBCKind::MBC5, true, true, false, false),
+            0x1C => (MBCKind::MBC5, false, false, false, true),
+            0x1D => (MBCKind::MBC5, true, false, false, true),
+            0x1E => (MBCKind::MBC5, true, true, false, true),
+            0x20 => (MBCKind::MBC6, false, false, false, false),
+            0x22 => (MBCKind::MBC7, true, true, false, true),
+            0xFC => (MBCKind::PocketCamera, true, false, false, false),
+            0xFD => (MBCKind::TAMA5, false, false, false, false),
+            0xFE => (MBCKind::HuC3, true, true, true, false),
+            0xFF => (MBCKind::HuC1, true, true, false, false),
+            _ => return Err(CartridgeError::UnknownCartridgeType(value)),
+        };
+        Ok(CartridgeType { code: value, mbc, ram, battery, timer, rumble })
+    }
+}
+#[derive(Clone, Copy, Debug)]
+pub enum Licensee
+{
+    Old(u8),
+    //Old code 0x33 means the two ASCII characters at 0x144 are used instead
+    New([u8; 2]),
+}
+impl fmt::Display for Licensee
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            Licensee::Old(code) => write!(f, "0x{:02X}", code),
+            Licensee::New(code) => write!(f, "\"{}{}\"", code[0] as char, code[1] as char),
+        }
+    }
+}
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader
+{
+    pub title: String,
+    pub cgb_support: CGBSupport,
+    pub sgb_support: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub licensee: Licensee,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+impl CartridgeHeader
+{
+    pub fn parse(rom: &[u8]) -> Result<CartridgeHeader, CartridgeError>
+    {
+        if rom.len() <= HEADER_END
+        {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+        let cgb_support = match rom[TITLE_END]
+        {
+            0xC0 => CGBSupport::Only,
+            0x80 => CGBSupport::Enhanced,
+            _ => CGBSupport::None,
+        };
+        //On CGB carts the last byte of the title is the CGB flag
+        let title_end = if cgb_support == CGBSupport::None {TITLE_END} else {TITLE_END - 1};
+        let title = rom[TITLE_START..=title_end].iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' {byte as char} else {'?'})
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        let cartridge_type = CartridgeType::try_from(rom[CARTRIDGE_TYPE])?;
+        let rom_size = match rom[ROM_SIZE]
+        {
+            code @ 0x00..=0x08 => (2 * ROM_BANK_SIZE) << code,
+            code => return Err(CartridgeError::UnknownRomSize(code)),
+        };
+        let ram_size = match rom[RAM_SIZE]
+        {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => RAM_BANK_SIZE,
+            0x03 => 4 * RAM_BANK_SIZE,
+            0x04 => 16 * RAM_BANK_SIZE,
+            0x05 => 8 * RAM_BANK_SIZE,
+            code => return Err(CartridgeError::UnknownRamSize(code)),
+        };
+        if rom.len() < rom_size
+        {
+            return Err(CartridgeError::Truncated { expected: rom_size, actual: rom.len() });
+        }
+        let licensee = match rom[OLD_LICENSEE_CODE]
+        {
+            0x33 => Licensee::New([rom[NEW_LICENSEE_CODE], rom[NEW_LICENSEE_CODE + 1]]),
+            code => Licensee::Old(code),
+        };
+
+        Ok(CartridgeHeader
+        {
+            title,
+            cgb_support,
+            sgb_support: rom[SGB_FLAG] == 0x03,
+            cartridge_type,
+            rom_size,
+            ram_size,
+            licensee,
+            version: rom[ROM_VERSION],
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: ((rom[GLOBAL_CHECKSUM] as u16) << 8) | rom[GLOBAL_CHECKSUM + 1] as u16,
+        })
+    }
+}
+//Same sum the boot ROM does over 0x134-0x14C
+pub fn header_checksum(rom: &[u8]) -> u8
+{
+    rom[TITLE_START..=ROM_VERSION].iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1))
+}
+//Sum of every byte except the checksum itself. Nothing on real hardware checks this.
+pub fn global_checksum(rom: &[u8]) -> u16
+{
+    rom.iter().enumerate()
+        .filter(|(i, _)| *i != GLOBAL_CHECKSUM && *i != GLOBAL_CHECKSUM + 1)
+        .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16))
+}
+
+//A blank image of the size the header codes give, with a valid header checksum. Each bank starts with its own number
+//so tests can see which one is mapped.
+#[cfg(test)]
+pub fn test_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8>
+{
+    let mut rom = vec![0; (2 * ROM_BANK_SIZE) << rom_size];
+    for (bank, data) in rom.chunks_mut(ROM_BANK_SIZE).enumerate()
+    {
+        data[0..2].copy_from_slice(&(bank as u16).to_le_bytes());
+    }
+    rom[TITLE_START..TITLE_START + 4].copy_from_slice(b"TEST");
+    rom[CARTRIDGE_TYPE] = cartridge_type;
+    rom[ROM_SIZE] = rom_size;
+    rom[RAM_SIZE] = ram_size;
+    rom[HEADER_CHECKSUM] = header_checksum(&rom);
+    rom
+}
+
+#[derive(Clone)]
+pub struct Cartridge
+{
+    pub header: CartridgeHeader,
+    pub rom: Vec<u8>,
+    pub ram: Vec<u8>,
+    pub mbc: MBC,
+    //Battery backed RAM or clock written since the last save
+    unsaved: bool,
+}
+impl Cartridge
+{
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Cartridge, CartridgeError>
+    {
+        let header = CartridgeHeader::parse(&rom)?;
+        let mbc = match header.cartridge_type.mbc
+        {
+            MBCKind::RomOnly => MBC::RomOnly,
+            MBCKind::MBC1 => MBC::MBC1(MBC1::new()),
+            MBCKind::MBC2 => MBC::MBC2(MBC2::new()),
+            MBCKind::MBC3 => MBC::MBC3(MBC3::new(if header.cartridge_type.timer {Some(RealTimeClock::new())} else {None})),
+            MBCKind::MBC5 => MBC::MBC5(MBC5::new(header.cartridge_type.rumble)),
+            kind => return Err(CartridgeError::UnsupportedMBC(kind)),
+        };
+        let ram_size = match header.cartridge_type.mbc
+        {
+            //The header says 0 for MBC2 even though the controller always has its own RAM
+            MBCKind::MBC2 => MBC2_RAM_SIZE,
+            _ if header.cartridge_type.ram => header.ram_size,
+            _ => 0,
+        };
+        Ok(Cartridge { header, rom, ram: vec![0; ram_size], mbc, unsaved: false })
+    }
+    pub fn read_rom(&self, address: usize) -> u8
+    {
+        self.mbc.read_rom(&self.rom, address)
+    }
+    pub fn write_rom(&mut self, address: usize, value: u8)
+    {
+        self.mbc.write_rom(address, value);
+    }
+    pub fn read_ram(&self, address: usize) -> u8
+    {
+        self.mbc.read_ram(&self.ram, address)
+    }
+    pub fn write_ram(&mut self, address: usize, value: u8)
+    {
+        self.mbc.write_ram(&mut self.ram, address, value);
+        self.unsaved |= self.header.cartridge_type.battery;
+    }
+    pub fn step(&mut self, cycles: u8)
+    {
+        self.mbc.step(cycles);
+    }
+    //What a power cycle does to the cartridge: the controller forgets its registers, battery backed parts don't
+    pub fn reset(&mut self)
+    {
+        self.mbc.reset();
+    }
+    pub fn rtc_mut(&mut self) -> Option<&mut RealTimeClock>
+    {
+        match &mut self.mbc
+        {
+            MBC::MBC3(mbc) => mbc.rtc.as_mut(),
+            _ => None,
+        }
+    }
+    pub fn has_battery(&self) -> bool
+    {
+        self.header.cartridge_type.battery
+    }
+    //Contents of a .sav file: the RAM, followed by the clock footer on MBC3 carts with a timer
+    pub fn save_data(&self) -> Vec<u8>
+    {
+        let mut data = self.ram.clone();
+        if let MBC::MBC3(MBC3 { rtc: Some(rtc), .. }) = &self.mbc
+        {
+            data.extend_from_slice(&rtc.to_footer());
+        }
+        data
+    }
+    //Takes save files from other emulators too. A short file only fills the start of the RAM, a missing or
+    //unrecognised clock footer leaves the clock as it is.
+    pub fn load_save_data(&mut self, data: &[u8])
+    {
+        let length = data.len().min(self.ram.len());
+        self.ram[..length].copy_from_slice(&data[..length]);
+        if let Some(rtc) = self.rtc_mut()
+        {
+            rtc.load_footer(&data[length..]);
+        }
+        self.unsaved = false;
+    }
+    //Save data to write out, if the game has written to battery backed memory and has since switched the RAM
+    //back off. Games disable the RAM once they're done saving, so this doesn't catch a save half written.
+    pub fn flush(&mut self) -> Option<Vec<u8>>
+    {
+        if !self.unsaved || self.mbc.ram_enabled()
+        {
+            return None;
+        }
+        self.unsaved = false;
+        Some(self.save_data())
+    }
+    //The boot ROM locks up on a cartridge whose header doesn't add up, without one it plays anyway
+    pub fn header_checksum_valid(&self) -> bool
+    {
+        header_checksum(&self.rom) == self.header.header_checksum
+    }
+    pub fn global_checksum_valid(&self) -> bool
+    {
+        global_checksum(&self.rom) == self.header.global_checksum
+    }
+}
+impl fmt::Display for Cartridge
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        let header = &self.header;
+        let kind = header.cartridge_type;
+        writeln!(f, "Title:           {}", header.title)?;
+        writeln!(f, "CGB support:     {:?}", header.cgb_support)?;
+        writeln!(f, "SGB support:     {}", header.sgb_support)?;
+        write!(f, "Cartridge type:  0x{:02X} {:?}", kind.code, kind.mbc)?;
+        for (present, name) in [(kind.ram, "RAM"), (kind.battery, "BATTERY"), (kind.timer, "TIMER"), (kind.rumble, "RUMBLE")]
+        {
+            if present
+            {
+                write!(f, "+{}", name)?;
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "ROM size:        {} KiB ({} banks)", header.rom_size / 1024, header.rom_size / ROM_BANK_SIZE)?;
+        writeln!(f, "RAM size:        {} KiB", header.ram_size / 1024)?;
+        writeln!(f, "Licensee:        {}", header.licensee)?;
+        writeln!(f, "Version:         {}", header.version)?;
+        writeln!(f, "Header checksum: 0x{:02X} ({})", header.header_checksum, if self.header_checksum_valid() {"ok"} else {"mismatch"})?;
+        write!(f, "Global checksum: 0x{:04X} ({})", header.global_checksum, if self.global_checksum_valid() {"ok"} else {"mismatch"})
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn parses_header()
+    {
+        let mut image = test_rom(0x03, 0x02, 0x03);
+        image[OLD_LICENSEE_CODE] = 0x33;
+        image[NEW_LICENSEE_CODE..NEW_LICENSEE_CODE + 2].copy_from_slice(b"01");
+        image[SGB_FLAG] = 0x03;
+        image[ROM_VERSION] = 2;
+        image[HEADER_CHECKSUM] = header_checksum(&image);
+        let header = CartridgeHeader::parse(&image).unwrap();
+        assert_eq!(header.title, "TEST");
+        assert_eq!(header.cgb_support, CGBSupport::None);
+        assert!(header.sgb_support);
+        assert_eq!(header.cartridge_type.mbc, MBCKind::MBC1);
+        assert!(header.cartridge_type.ram && header.cartridge_type.battery);
+        assert_eq!(header.rom_size, 128 * 1024);
+        assert_eq!(header.ram_size, 32 * 1024);
+        assert_eq!(header.licensee.to_string(), "\"01\"");
+        assert_eq!(header.version, 2);
+    }
+
+    #[test]
+    fn cgb_flag_is_not_part_of_the_title()
+    {
+        let mut image = test_rom(0x00, 0x00, 0x00);
+        image[TITLE_START..=TITLE_END].copy_from_slice(b"ABCDEFGHIJKLMNO\x80");
+        image[HEADER_CHECKSUM] = header_checksum(&image);
+        let header = CartridgeHeader::parse(&image).unwrap();
+        assert_eq!(header.cgb_support, CGBSupport::Enhanced);
+        assert_eq!(header.title, "ABCDEFGHIJKLMNO");
+    }
+
+    #[test]
+    fn rejects_bad_headers()
+    {
+        assert!(matches!(CartridgeHeader::parse(&[0; 0x100]), Err(CartridgeError::TooSmall(0x100))));
+        assert!(matches!(CartridgeHeader::parse(&test_rom(0x04, 0x00, 0x00)), Err(CartridgeError::UnknownCartridgeType(0x04))));
+        assert!(matches!(CartridgeHeader::parse(&test_rom(0x00, 0x00, 0x06)), Err(CartridgeError::UnknownRamSize(0x06))));
+        let mut truncated = test_rom(0x01, 0x02, 0x00);
+        truncated.truncate(2 * ROM_BANK_SIZE);
+        assert!(matches!(CartridgeHeader::parse(&truncated), Err(CartridgeError::Truncated { expected: 0x20000, actual: 0x8000 })));
+        assert!(matches!(Cartridge::from_bytes(test_rom(0x20, 0x00, 0x00)), Err(CartridgeError::UnsupportedMBC(MBCKind::MBC6))));
+    }
+
+    #[test]
+    fn bad_header_checksum_still_loads()
+    {
+        let mut image = test_rom(0x00, 0x00, 0x00);
+        assert!(Cartridge::from_bytes(image.clone()).unwrap().header_checksum_valid());
+        image[HEADER_CHECKSUM] ^= 0xFF;
+        let cartridge = Cartridge::from_bytes(image).unwrap();
+        assert!(!cartridge.header_checksum_valid());
+    }
+
+    #[test]
+    fn save_data_with_clock_footer()
+    {
+        let mut cartridge = Cartridge::from_bytes(test_rom(0x10, 0x00, 0x03)).unwrap();
+        cartridge.ram[0] = 0x12;
+        cartridge.rtc_mut().unwrap().write(crate::RTC::RTC_MINUTES, 9);
+        let data = cartridge.save_data();
+        assert_eq!(data.len(), 4 * RAM_BANK_SIZE + crate::RTC::RTC_FOOTER_SIZE);
+
+        let mut loaded = Cartridge::from_bytes(test_rom(0x10, 0x00, 0x03)).unwrap();
+        loaded.load_save_data(&data);
+        assert_eq!(loaded.ram[0], 0x12);
+        assert_eq!(loaded.rtc_mut().unwrap().minutes, 9);
+        //RAM only saves from other emulators leave the clock alone
+        let mut loaded = Cartridge::from_bytes(test_rom(0x10, 0x00, 0x03)).unwrap();
+        loaded.load_save_data(&data[..RAM_BANK_SIZE]);
+        assert_eq!(loaded.ram[0], 0x12);
+        assert_eq!(loaded.rtc_mut().unwrap().minutes, 0);
+    }
+
+    #[test]
+    fn flush_waits_for_ram_to_be_disabled()
+    {
+        let mut cartridge = Cartridge::from_bytes(test_rom(0x03, 0x00, 0x02)).unwrap();
+        assert!(cartridge.flush().is_none());
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x56);
+        assert!(cartridge.flush().is_none());
+        cartridge.write_rom(0x0000, 0x00);
+        assert_eq!(cartridge.flush().unwrap()[0], 0x56);
+        assert!(cartridge.flush().is_none());
+    }
+}
@@ -0,0 +1,461 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::Cartridge::{self, ROM_BANK_SIZE};
+use crate::Model::Model;
+use crate::{GameBoy, CPU_CLOCK_HZ, CYCLES_PER_FRAME};
+
+const MAGIC: &[u8] = b"GBS";
+const HEADER_SIZE: usize = 0x70;
+const TITLE_START: usize = 0x10;
+const AUTHOR_START: usize = 0x30;
+const COPYRIGHT_START: usize = 0x50;
+const TEXT_SIZE: usize = 32;
+
+//The driver lives below the lowest address the GBS code is allowed to load at
+const MIN_LOAD_ADDRESS: u16 = 0x0400;
+const PLAY_HANDLER: usize = 0x0068;
+const DRIVER: usize = 0x0080;
+const VBLANK_VECTOR: usize = 0x0040;
+const TIMER_VECTOR: usize = 0x0050;
+//MBC1 with 8KB of RAM, which is what GBS rips expect to be able to bank switch and use. Bank writes to 0x2000
+//only reach 512KB on an MBC1, anything bigger goes on an MBC5 with RAM, which takes the same writes.
+const MBC1_CARTRIDGE_TYPE: u8 = 0x02;
+const MBC1_MAX_ROM_SIZE_CODE: u8 = 0x04;
+const MBC5_CARTRIDGE_TYPE: u8 = 0x1A;
+const RAM_SIZE: u8 = 0x02;
+//8MB, all 512 of an MBC5's banks
+const MAX_ROM_SIZE_CODE: u32 = 0x08;
+
+#[derive(Debug)]
+pub enum GbsError
+{
+    TooSmall(usize),
+    //Doesn't start with "GBS"
+    NotGbs,
+    UnsupportedVersion(u8),
+    NoSongs,
+    //Loading under 0x400 would overwrite the RST and interrupt vectors
+    LoadAddress(u16),
+    //More than the biggest ROM an MBC5 can bank in
+    TooLarge(usize),
+}
+impl fmt::Display for GbsError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            GbsError::TooSmall(size) => write!(f, "file is {} bytes, too small to contain a GBS header", size),
+            GbsError::NotGbs => write!(f, "not a GBS file"),
+            GbsError::UnsupportedVersion(version) => write!(f, "unsupported GBS version {}", version),
+            GbsError::NoSongs => write!(f, "GBS file has no songs"),
+            GbsError::LoadAddress(address) => write!(f, "load address 0x{:04X} is below 0x{:04X}", address, MIN_LOAD_ADDRESS),
+            GbsError::TooLarge(size) => write!(f, "{} bytes of code is too large to map into ROM", size),
+        }
+    }
+}
+impl std::error::Error for GbsError {}
+
+fn read_word(bytes: &[u8], offset: usize) -> u16
+{
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+fn read_text(bytes: &[u8], offset: usize) -> String
+{
+    bytes[offset..offset + TEXT_SIZE].iter()
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' {byte as char} else {'?'})
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+//A Game Boy Sound file, the sound driver and music data ripped out of a game with a header saying how to run it
+pub struct Gbs
+{
+    pub version: u8,
+    pub song_count: u8,
+    //1 based, like the header
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    pub data: Vec<u8>,
+}
+impl Gbs
+{
+    pub fn is_gbs(bytes: &[u8]) -> bool
+    {
+        bytes.starts_with(MAGIC)
+    }
+    pub fn parse(bytes: &[u8]) -> Result<Gbs, GbsError>
+    {
+        if bytes.len() < HEADER_SIZE
+        {
+            return Err(GbsError::TooSmall(bytes.len()));
+        }
+        if !Gbs::is_gbs(bytes)
+        {
+            return Err(GbsError::NotGbs);
+        }
+        let version = bytes[0x03];
+        if version != 1
+        {
+            return Err(GbsError::UnsupportedVersion(version));
+        }
+        let song_count = bytes[0x04];
+        if song_count == 0
+        {
+            return Err(GbsError::NoSongs);
+        }
+        let load_address = read_word(bytes, 0x06);
+        if load_address < MIN_LOAD_ADDRESS
+        {
+            return Err(GbsError::LoadAddress(load_address));
+        }
+        let data = bytes[HEADER_SIZE..].to_vec();
+        if load_address as usize + data.len() > (2 * ROM_BANK_SIZE) << MAX_ROM_SIZE_CODE
+        {
+            return Err(GbsError::TooLarge(data.len()));
+        }
+        Ok(Gbs
+        {
+            version,
+            song_count,
+            first_song: bytes[0x05].clamp(1, song_count),
+            load_address,
+            init_address: read_word(bytes, 0x08),
+            play_address: read_word(bytes, 0x0A),
+            stack_pointer: read_word(bytes, 0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title: read_text(bytes, TITLE_START),
+            author: read_text(bytes, AUTHOR_START),
+            copyright: read_text(bytes, COPYRIGHT_START),
+            data,
+        })
+    }
+    //TAC bit 2 in the header means PLAY runs off the timer interrupt, otherwise it's once per VBlank.
+    //Bit 7 asks for CGB double speed, which a DMG can't do.
+    pub fn uses_timer(&self) -> bool
+    {
+        (self.timer_control & 0x04) != 0
+    }
+    //A cartridge image that starts the given track (0 based). The code sits at its load address with a small
+    //driver underneath that sets the timer up, calls INIT with the track in A and then calls PLAY from the
+    //VBlank or timer interrupt.
+    pub fn rom(&self, track: u8) -> Vec<u8>
+    {
+        let end = self.load_address as usize + self.data.len();
+        let size = end.max(2 * ROM_BANK_SIZE).next_power_of_two();
+        let mut rom = vec![0; size];
+        rom[self.load_address as usize..end].copy_from_slice(&self.data);
+
+        //RST n jumps to the same offset from the load address
+        for vector in (0..0x40).step_by(8)
+        {
+            let [low, high] = (self.load_address + vector as u16).to_le_bytes();
+            rom[vector..vector + 3].copy_from_slice(&[0xC3, low, high]);
+        }
+        for vector in (0x40..=0x60).step_by(8)
+        {
+            rom[vector] = 0xD9; //RETI
+        }
+        let play_vector = if self.uses_timer() {TIMER_VECTOR} else {VBLANK_VECTOR};
+        rom[play_vector..play_vector + 3].copy_from_slice(&[0xC3, PLAY_HANDLER as u8, 0x00]);
+
+        let [play_low, play_high] = self.play_address.to_le_bytes();
+        rom[PLAY_HANDLER..PLAY_HANDLER + 12].copy_from_slice(&
+        [
+            0xF5, 0xC5, 0xD5, 0xE5,   //PUSH AF, BC, DE, HL
+            0xCD, play_low, play_high, //CALL PLAY
+            0xE1, 0xD1, 0xC1, 0xF1,   //POP HL, DE, BC, AF
+            0xD9,                     //RETI
+        ]);
+
+        let [sp_low, sp_high] = self.stack_pointer.to_le_bytes();
+        let [init_low, init_high] = self.init_address.to_le_bytes();
+        let interrupt = if self.uses_timer() {0x04} else {0x01};
+        let driver =
+        [
+            0xF3,                         //DI
+            0x31, sp_low, sp_high,        //LD SP, stack pointer
+            0x3E, 0x0A, 0xEA, 0x00, 0x00, //Enable cartridge RAM
+            0x3E, self.timer_modulo, 0xE0, 0x06,
+            0x3E, self.timer_control & 0x07, 0xE0, 0x07,
+            0x3E, interrupt, 0xE0, 0xFF,  //IE
+            0x3E, track,                  //LD A, track
+            0xCD, init_low, init_high,    //CALL INIT
+            0xAF, 0xE0, 0x0F,             //Clear IF
+            0xFB,                         //EI
+            0x76,                         //HALT
+            0x18, 0xFD,                   //JR back to the HALT
+        ];
+        rom[DRIVER..DRIVER + driver.len()].copy_from_slice(&driver);
+        rom[0x0100..0x0103].copy_from_slice(&[0xC3, DRIVER as u8, 0x00]);
+
+        let size_code = (size / (2 * ROM_BANK_SIZE)).trailing_zeros() as u8;
+        rom[0x0147] = if size_code <= MBC1_MAX_ROM_SIZE_CODE {MBC1_CARTRIDGE_TYPE} else {MBC5_CARTRIDGE_TYPE};
+        rom[0x0148] = size_code;
+        rom[0x0149] = RAM_SIZE;
+        rom[0x014D] = Cartridge::header_checksum(&rom);
+        rom
+    }
+}
+impl fmt::Display for Gbs
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        writeln!(f, "Title:          {}", self.title)?;
+        writeln!(f, "Author:         {}", self.author)?;
+        writeln!(f, "Copyright:      {}", self.copyright)?;
+        writeln!(f, "Songs:          {} (first {})", self.song_count, self.first_song)?;
+        writeln!(f, "Load:           0x{:04X}", self.load_address)?;
+        writeln!(f, "Init:           0x{:04X}", self.init_address)?;
+        writeln!(f, "Play:           0x{:04X}", self.play_address)?;
+        writeln!(f, "Stack pointer:  0x{:04X}", self.stack_pointer)?;
+        write!(f, "Play rate:      {}", if self.uses_timer() {format!("timer (TMA 0x{:02X}, TAC 0x{:02X})", self.timer_modulo, self.timer_control)} else {"VBlank".to_string()})
+    }
+}
+
+//Runs a GBS file one track at a time. Tracks are 0 based, and each can be given a length after which
+//run_frame reports it finished.
+pub struct GbsPlayer
+{
+    gbs: Gbs,
+    gameboy: GameBoy,
+    track: u8,
+    //Cycles the current track has been playing
+    cycles: u64,
+    default_length: Option<Duration>,
+    track_lengths: Vec<Option<Duration>>,
+}
+impl GbsPlayer
+{
+    pub fn new(gbs: Gbs, model: Model) -> GbsPlayer
+    {
+        let track = gbs.first_song - 1;
+        let gameboy = GameBoy::new(None, gbs.rom(track), model).expect("INVALID GBS ROM IMAGE");
+        let track_lengths = vec![None; gbs.song_count as usize];
+        GbsPlayer { gbs, gameboy, track, cycles: 0, default_length: None, track_lengths }
+    }
+    pub fn gbs(&self) -> &Gbs
+    {
+        &self.gbs
+    }
+    pub fn gameboy(&self) -> &GameBoy
+    {
+        &self.gameboy
+    }
+    pub fn gameboy_mut(&mut self) -> &mut GameBoy
+    {
+        &mut self.gameboy
+    }
+    pub fn track(&self) -> u8
+    {
+        self.track
+    }
+    //Used for every track without a length of its own. None plays forever.
+    pub fn set_default_length(&mut self, length: Option<Duration>)
+    {
+        self.default_length = length;
+    }
+    pub fn set_track_length(&mut self, track: u8, length: Option<Duration>)
+    {
+        if let Some(entry) = self.track_lengths.get_mut(track as usize)
+        {
+            *entry = length;
+        }
+    }
+    pub fn track_length(&self, track: u8) -> Option<Duration>
+    {
+        self.track_lengths.get(track as usize).copied().flatten().or(self.default_length)
+    }
+    //How long the current track has been playing, in emulated time
+    pub fn elapsed(&self) -> Duration
+    {
+        Duration::from_nanos(self.cycles * 1_000_000_000 / CPU_CLOCK_HZ as u64)
+    }
+    //Power cycle into the given track. Audio settings on the GameBoy carry over.
+    pub fn start_track(&mut self, track: u8)
+    {
+        self.track = track % self.gbs.song_count;
+        self.cycles = 0;
+        self.gameboy.load_rom(self.gbs.rom(self.track)).expect("INVALID GBS ROM IMAGE");
+    }
+    pub fn next_track(&mut self)
+    {
+        self.start_track((self.track + 1) % self.gbs.song_count);
+    }
+    pub fn previous_track(&mut self)
+    {
+        self.start_track(self.track.checked_sub(1).unwrap_or(self.gbs.song_count - 1));
+    }
+    //Returns true once the current track has played for its whole length
+    pub fn run_frame(&mut self) -> bool
+    {
+        self.gameboy.run_frame();
+        self.cycles += CYCLES_PER_FRAME as u64;
+        self.track_length(self.track).is_some_and(|length| self.elapsed() >= length)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::Cartridge::Cartridge;
+
+    const LOAD: u16 = 0x0400;
+
+    //Three songs. INIT stores the track it was given at 0xC000, PLAY counts its calls at 0xC001.
+    fn gbs_file(timer_control: u8) -> Vec<u8>
+    {
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[0..3].copy_from_slice(MAGIC);
+        bytes[0x03] = 1;
+        bytes[0x04] = 3;
+        bytes[0x05] = 2;
+        bytes[0x06..0x08].copy_from_slice(&LOAD.to_le_bytes());
+        bytes[0x08..0x0A].copy_from_slice(&LOAD.to_le_bytes());
+        bytes[0x0A..0x0C].copy_from_slice(&(LOAD + 8).to_le_bytes());
+        bytes[0x0C..0x0E].copy_from_slice(&0xDFFFu16.to_le_bytes());
+        bytes[0x0F] = timer_control;
+        bytes[TITLE_START..TITLE_START + 4].copy_from_slice(b"Test");
+        bytes[AUTHOR_START..AUTHOR_START + 2].copy_from_slice(b"Me");
+        bytes.extend_from_slice(&
+        [
+            0xEA, 0x00, 0xC0,       //LD (0xC000), A
+            0xC9,                   //RET
+            0x00, 0x00, 0x00, 0x00,
+            0xFA, 0x01, 0xC0,       //LD A, (0xC001)
+            0x3C,                   //INC A
+            0xEA, 0x01, 0xC0,       //LD (0xC001), A
+            0xC9,                   //RET
+        ]);
+        bytes
+    }
+
+    #[test]
+    fn parses_header()
+    {
+        let gbs = Gbs::parse(&gbs_file(0)).unwrap();
+        assert_eq!((gbs.song_count, gbs.first_song), (3, 2));
+        assert_eq!((gbs.load_address, gbs.init_address, gbs.play_address, gbs.stack_pointer), (LOAD, LOAD, LOAD + 8, 0xDFFF));
+        assert_eq!((gbs.title.as_str(), gbs.author.as_str(), gbs.copyright.as_str()), ("Test", "Me", ""));
+        assert_eq!(gbs.data.len(), 16);
+        assert!(!gbs.uses_timer());
+        assert!(Gbs::parse(&gbs_file(0x04)).unwrap().uses_timer());
+    }
+
+    #[test]
+    fn rejects_bad_files()
+    {
+        assert!(matches!(Gbs::parse(&[0; 0x20]), Err(GbsError::TooSmall(0x20))));
+        let mut bytes = gbs_file(0);
+        bytes[0] = b'X';
+        assert!(matches!(Gbs::parse(&bytes), Err(GbsError::NotGbs)));
+        let mut bytes = gbs_file(0);
+        bytes[0x03] = 2;
+        assert!(matches!(Gbs::parse(&bytes), Err(GbsError::UnsupportedVersion(2))));
+        let mut bytes = gbs_file(0);
+        bytes[0x04] = 0;
+        assert!(matches!(Gbs::parse(&bytes), Err(GbsError::NoSongs)));
+        let mut bytes = gbs_file(0);
+        bytes[0x06..0x08].copy_from_slice(&0x0200u16.to_le_bytes());
+        assert!(matches!(Gbs::parse(&bytes), Err(GbsError::LoadAddress(0x0200))));
+        let mut bytes = gbs_file(0);
+        bytes.resize(HEADER_SIZE + (8 << 20), 0);
+        assert!(matches!(Gbs::parse(&bytes), Err(GbsError::TooLarge(_))));
+    }
+
+    #[test]
+    fn builds_a_cartridge()
+    {
+        let gbs = Gbs::parse(&gbs_file(0)).unwrap();
+        let rom = gbs.rom(1);
+        assert_eq!(rom.len(), 2 * ROM_BANK_SIZE);
+        assert_eq!(&rom[LOAD as usize..LOAD as usize + 4], &[0xEA, 0x00, 0xC0, 0xC9]);
+        //RST 0x08 goes to the same offset from the load address, VBlank to the PLAY handler
+        assert_eq!(&rom[0x08..0x0B], &[0xC3, 0x08, 0x04]);
+        assert_eq!(&rom[VBLANK_VECTOR..VBLANK_VECTOR + 3], &[0xC3, PLAY_HANDLER as u8, 0x00]);
+        assert_eq!(rom[TIMER_VECTOR], 0xD9);
+        let cartridge = Cartridge::from_bytes(rom).unwrap();
+        assert!(cartridge.header_checksum_valid());
+        assert_eq!(cartridge.header.cartridge_type.code, MBC1_CARTRIDGE_TYPE);
+        assert_eq!(cartridge.ram.len(), 0x2000);
+    }
+
+    #[test]
+    fn player_calls_init_and_play()
+    {
+        let mut player = GbsPlayer::new(Gbs::parse(&gbs_file(0)).unwrap(), Model::DMG);
+        assert_eq!(player.track(), 1);
+        for _ in 0..60
+        {
+            player.run_frame();
+        }
+        assert_eq!(player.gameboy_mut().cpu_mut().bus.read_byte(0xC000), 1);
+        let calls = player.gameboy_mut().cpu_mut().bus.read_byte(0xC001);
+        assert!((59..=60).contains(&calls), "{}", calls);
+    }
+
+    #[test]
+    fn timer_rate_play()
+    {
+        //TAC 4096 Hz with TMA 0 overflows 16 times a second
+        let mut player = GbsPlayer::new(Gbs::parse(&gbs_file(0x04)).unwrap(), Model::DMG);
+        for _ in 0..60
+        {
+            player.run_frame();
+        }
+        let calls = player.gameboy_mut().cpu_mut().bus.read_byte(0xC001);
+        assert!((15..=16).contains(&calls), "{}", calls);
+    }
+
+    #[test]
+    fn track_changes_wrap()
+    {
+        let mut player = GbsPlayer::new(Gbs::parse(&gbs_file(0)).unwrap(), Model::DMG);
+        player.next_track();
+        assert_eq!(player.track(), 2);
+        player.next_track();
+        assert_eq!(player.track(), 0);
+        player.previous_track();
+        assert_eq!(player.track(), 2);
+        player.run_frame();
+        assert_eq!(player.gameboy_mut().cpu_mut().bus.read_byte(0xC000), 2);
+    }
+
+    #[test]
+    fn track_lengths()
+    {
+        let mut player = GbsPlayer::new(Gbs::parse(&gbs_file(0)).unwrap(), Model::DMG);
+        player.set_default_length(Some(Duration::from_secs(2)));
+        player.set_track_length(1, Some(Duration::from_millis(500)));
+        assert_eq!(player.track_length(0), Some(Duration::from_secs(2)));
+        let frames = (1..).find(|_| player.run_frame()).unwrap();
+        assert_eq!(frames, 30);
+        player.next_track();
+        assert!(player.elapsed().is_zero());
+    }
+
+    #[test]
+    fn large_images_use_mbc5()
+    {
+        let mut bytes = gbs_file(0);
+        bytes.resize(HEADER_SIZE + (600 << 10), 0);
+        let rom = Gbs::parse(&bytes).unwrap().rom(0);
+        assert_eq!(rom.len(), 1 << 20);
+        let cartridge = Cartridge::from_bytes(rom).unwrap();
+        assert_eq!(cartridge.header.cartridge_type.code, MBC5_CARTRIDGE_TYPE);
+        assert_eq!(cartridge.header.rom_size, 1 << 20);
+    }
+}
@@ -1,3 +1,28 @@
+//The five interrupt sources in priority order, each numbered by its bit in IF and IE
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Interrupt
+{
+    VBlank = 0,
+    LCDStat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+impl Interrupt
+{
+    pub const ALL: [Interrupt; 5] = [Interrupt::VBlank, Interrupt::LCDStat, Interrupt::Timer, Interrupt::Serial, Interrupt::Joypad];
+
+    pub fn bit(self) -> u8
+    {
+        1 << self as u8
+    }
+    //Where the CPU jumps to service it, 0x40 onwards in steps of 8
+    pub fn vector(self) -> u16
+    {
+        0x0040 + 8 * self as u16
+    }
+}
+
 pub struct InterruptFlags 
 {
     pub vblank: bool,
@@ -31,11 +56,32 @@ impl InterruptFlags
 
     pub fn to_byte(&mut self) -> u8 
     {
-        0b11100000 | // unused bits always read as 1s
-               ((if self.joypad { 1 } else { 0 }) << 4) |
-               ((if self.serial { 1 } else { 0 }) << 3) |
-               ((if self.timer { 1 } else { 0 }) << 2) |
-               ((if self.lcdstat { 1 } else { 0 }) << 1) |
-               (if self.vblank { 1 } else { 0 })
+        ((if self.joypad { 1 } else { 0 }) << 4) |
+        ((if self.serial { 1 } else { 0 }) << 3) |
+        ((if self.timer { 1 } else { 0 }) << 2) |
+        ((if self.lcdstat { 1 } else { 0 }) << 1) |
+        (if self.vblank { 1 } else { 0 })
     }
-}
\ No newline at end of file
+
+    fn flag_mut(&mut self, interrupt: Interrupt) -> &mut bool
+    {
+        match interrupt
+        {
+            Interrupt::VBlank => &mut self.vblank,
+            Interrupt::LCDStat => &mut self.lcdstat,
+            Interrupt::Timer => &mut self.timer,
+            Interrupt::Serial => &mut self.serial,
+            Interrupt::Joypad => &mut self.joypad,
+        }
+    }
+
+    pub fn request(&mut self, interrupt: Interrupt)
+    {
+        *self.flag_mut(interrupt) = true;
+    }
+
+    pub fn acknowledge(&mut self, interrupt: Interrupt)
+    {
+        *self.flag_mut(interrupt) = false;
+    }
+}
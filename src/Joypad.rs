@@ -1,57 +1,78 @@
+//Host side view of the eight buttons, true means the button is held down
+#[derive(Clone, Copy, Default)]
+pub struct Buttons
+{
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool
+}
 #[derive(Clone, Copy)]
 pub struct Joypad
 {
+    //The CPU picks which half of the buttons it wants to see through bits 4 and 5 of 0xFF00, true means that half is selected
     select_buttons: bool,
     select_dpad: bool,
-    start_down: bool,
-    select_up: bool,
-    b_left: bool,
-    a_right: bool
-}
-impl std::convert::From<u8> for Joypad
-{
-    fn from(value: u8) -> Self 
-    {
-        Joypad
-        {
-            select_buttons: (value & 32) == 0,
-            select_dpad: (value & 16) == 0,
-            start_down: (value & 8) == 0,
-            select_up: (value & 4) == 0,
-            b_left: (value & 2) == 0,
-            a_right: (value & 1) == 0,
-        }    
-    }
+    buttons: Buttons
 }
 impl std::convert::From<Joypad> for u8
 {
-    fn from(value: Joypad) -> Self 
+    fn from(value: Joypad) -> Self
     {
-        let reg: u8 = ((value.select_buttons as u8) << 5) | ((value.select_dpad as u8) << 4) | ((value.start_down as u8) << 3) | ((value.select_up as u8) << 2 | ((value.b_left as u8) << 1)) | (value.a_right as u8);
-        reg
+        //Everything on this register is active low, so a pressed button in a selected group reads as 0
+        let pressed = value.buttons;
+        let mut lines: u8 = 0;
+        if value.select_buttons
+        {
+            lines |= ((pressed.start as u8) << 3) | ((pressed.select as u8) << 2) | ((pressed.b as u8) << 1) | (pressed.a as u8);
+        }
+        if value.select_dpad
+        {
+            lines |= ((pressed.down as u8) << 3) | ((pressed.up as u8) << 2) | ((pressed.left as u8) << 1) | (pressed.right as u8);
+        }
+        0b11000000 | ((!value.select_buttons as u8) << 5) | ((!value.select_dpad as u8) << 4) | (!lines & 0x0F)
     }
 }
 impl Joypad
 {
     pub fn new() -> Self
     {
-        Joypad 
-        { 
-            select_buttons: true,
-            select_dpad: true,
-            start_down: true,
-            select_up: true,
-            b_left: true,
-            a_right: true 
+        Joypad
+        {
+            select_buttons: false,
+            select_dpad: false,
+            buttons: Buttons::default()
         }
     }
+    //The four input lines, low bits of 0xFF00
+    fn lines(&self) -> u8
+    {
+        u8::from(*self) & 0x0F
+    }
+    //The joypad interrupt fires when one of the four input lines goes from high to low, either from a button
+    //being pressed or a group with a button already held being selected. select and set_buttons both return
+    //true when it does. Only the two select bits of 0xFF00 are writable.
+    pub fn select(&mut self, value: u8) -> bool
+    {
+        let before = self.lines();
+        self.select_buttons = (value & 32) == 0;
+        self.select_dpad = (value & 16) == 0;
+        before & !self.lines() != 0
+    }
+    pub fn set_buttons(&mut self, buttons: Buttons) -> bool
+    {
+        let before = self.lines();
+        self.buttons = buttons;
+        before & !self.lines() != 0
+    }
     pub fn reset_joypad(&mut self)
     {
-        self.select_buttons = true;
-        self.select_dpad = true;
-        self.start_down = true;
-        self.select_up = true;
-        self.b_left = true;
-        self.a_right = true;
+        self.select_buttons = false;
+        self.select_dpad = false;
+        self.buttons = Buttons::default();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,584 @@
+use crate::Cartridge::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::Memory::{CARTRIDGE_RAM_START, GAME_ROM_BANK_N_START};
+use crate::RTC::{RealTimeClock, RTC_DAY_HIGH, RTC_SECONDS};
+
+pub const RAM_ENABLE_START: usize = 0x0000;
+pub const RAM_ENABLE_END: usize = 0x1FFF;
+pub const ROM_BANK_NUMBER_START: usize = 0x2000;
+pub const ROM_BANK_NUMBER_END: usize = 0x3FFF;
+//MBC5 splits the ROM bank register in two
+pub const ROM_BANK_LOW_END: usize = 0x2FFF;
+pub const ROM_BANK_HIGH_START: usize = 0x3000;
+pub const RAM_BANK_NUMBER_START: usize = 0x4000;
+pub const RAM_BANK_NUMBER_END: usize = 0x5FFF;
+pub const BANKING_MODE_START: usize = 0x6000;
+pub const BANKING_MODE_END: usize = 0x7FFF;
+
+//Absolute offset into the ROM image for a bank and an address inside the 16 KiB window.
+//Bank numbers past the end of the ROM wrap around, as the unused upper bank lines aren't connected.
+fn rom_offset(rom: &[u8], bank: usize, address: usize) -> usize
+{
+    (bank * ROM_BANK_SIZE + (address % ROM_BANK_SIZE)) % rom.len()
+}
+fn ram_offset(ram: &[u8], bank: usize, address: usize) -> usize
+{
+    (bank * RAM_BANK_SIZE + (address - CARTRIDGE_RAM_START)) % ram.len()
+}
+
+//Which memory bank controller sits between the CPU and the cartridge ROM/RAM
+#[derive(Clone)]
+pub enum MBC
+{
+    //32 KiB mapped flat, with at most one 8 KiB RAM chip
+    RomOnly,
+    MBC1(MBC1),
+    MBC2(MBC2),
+    MBC3(MBC3),
+    MBC5(MBC5),
+}
+impl MBC
+{
+    pub fn read_rom(&self, rom: &[u8], address: usize) -> u8
+    {
+        match self
+        {
+            MBC::RomOnly => rom[address % rom.len()],
+            MBC::MBC1(mbc) => mbc.read_rom(rom, address),
+            MBC::MBC2(mbc) => mbc.read_rom(rom, address),
+            MBC::MBC3(mbc) => mbc.read_rom(rom, address),
+            MBC::MBC5(mbc) => mbc.read_rom(rom, address),
+        }
+    }
+    //The ROM can't be written to, writes into its address range program the controller instead
+    pub fn write_rom(&mut self, address: usize, value: u8)
+    {
+        match self
+        {
+            MBC::RomOnly => {},
+            MBC::MBC1(mbc) => mbc.write_register(address, value),
+            MBC::MBC2(mbc) => mbc.write_register(address, value),
+            MBC::MBC3(mbc) => mbc.write_register(address, value),
+            MBC::MBC5(mbc) => mbc.write_register(address, value),
+        }
+    }
+    //0xA000-0xBFFF, reads as open bus (0xFF) when there's nothing there or it's disabled
+    pub fn read_ram(&self, ram: &[u8], address: usize) -> u8
+    {
+        match self
+        {
+            MBC::RomOnly => if ram.is_empty() {0xFF} else {ram[ram_offset(ram, 0, address)]},
+            MBC::MBC1(mbc) => mbc.read_ram(ram, address),
+            MBC::MBC2(mbc) => mbc.read_ram(ram, address),
+            MBC::MBC3(mbc) => mbc.read_ram(ram, address),
+            MBC::MBC5(mbc) => mbc.read_ram(ram, address),
+        }
+    }
+    pub fn write_ram(&mut self, ram: &mut [u8], address: usize, value: u8)
+    {
+        match self
+        {
+            MBC::RomOnly => if !ram.is_empty() {ram[ram_offset(ram, 0, address)] = value},
+            MBC::MBC1(mbc) => mbc.write_ram(ram, address, value),
+            MBC::MBC2(mbc) => mbc.write_ram(ram, address, value),
+            MBC::MBC3(mbc) => mbc.write_ram(ram, address, value),
+            MBC::MBC5(mbc) => mbc.write_ram(ram, address, value),
+        }
+    }
+    //Only the clock on MBC3 needs to see time passing
+    pub fn step(&mut self, cycles: u8)
+    {
+        if let MBC::MBC3(MBC3 { rtc: Some(rtc), .. }) = self
+        {
+            rtc.step(cycles);
+        }
+    }
+    //Back to power on state. Anything battery backed (RAM, the clock) is left alone.
+    pub fn reset(&mut self)
+    {
+        match self
+        {
+            MBC::RomOnly => {},
+            MBC::MBC1(mbc) => *mbc = MBC1::new(),
+            MBC::MBC2(mbc) => *mbc = MBC2::new(),
+            MBC::MBC3(mbc) => *mbc = MBC3::new(mbc.rtc.take()),
+            MBC::MBC5(mbc) => *mbc = MBC5::new(mbc.has_rumble),
+        }
+    }
+    //Whether the game currently has the RAM switched on. Without a controller there's no switch,
+    //so the RAM never counts as open.
+    pub fn ram_enabled(&self) -> bool
+    {
+        match self
+        {
+            MBC::RomOnly => false,
+            MBC::MBC1(mbc) => mbc.ram_enabled,
+            MBC::MBC2(mbc) => mbc.ram_enabled,
+            MBC::MBC3(mbc) => mbc.ram_enabled,
+            MBC::MBC5(mbc) => mbc.ram_enabled,
+        }
+    }
+    //Whether a rumble cartridge currently has its motor switched on
+    pub fn rumble(&self) -> bool
+    {
+        match self
+        {
+            MBC::MBC5(mbc) => mbc.rumble,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MBC1
+{
+    ram_enabled: bool,
+    //5 bit register, a 0 written here selects bank 1
+    rom_bank: u8,
+    //2 bit register, either bits 5-6 of the ROM bank or the RAM bank depending on the mode
+    upper_bank: u8,
+    //false: 0x0000-0x3FFF is always bank 0 and RAM is always bank 0
+    //true: the upper bits also apply to 0x0000-0x3FFF and select the RAM bank
+    advanced_banking: bool,
+}
+impl MBC1
+{
+    pub fn new() -> MBC1
+    {
+        MBC1
+        {
+            ram_enabled: false,
+            rom_bank: 1,
+            upper_bank: 0,
+            advanced_banking: false,
+        }
+    }
+    pub fn write_register(&mut self, address: usize, value: u8)
+    {
+        match address
+        {
+            RAM_ENABLE_START..=RAM_ENABLE_END => self.ram_enabled = (value & 0x0F) == 0x0A,
+            //Only the 5 bit register is checked for 0, which is why banks 0x20, 0x40 and 0x60 can't be reached
+            //through 0x4000-0x7FFF and give 0x21, 0x41 and 0x61 instead
+            ROM_BANK_NUMBER_START..=ROM_BANK_NUMBER_END => self.rom_bank = if value & 0x1F == 0 {1} else {value & 0x1F},
+            RAM_BANK_NUMBER_START..=RAM_BANK_NUMBER_END => self.upper_bank = value & 0b11,
+            BANKING_MODE_START..=BANKING_MODE_END => self.advanced_banking = (value & 0x01) != 0,
+            _ => {},
+        }
+    }
+    pub fn read_rom(&self, rom: &[u8], address: usize) -> u8
+    {
+        let bank = if address < GAME_ROM_BANK_N_START
+        {
+            if self.advanced_banking {(self.upper_bank as usize) << 5} else {0}
+        }
+        else
+        {
+            ((self.upper_bank as usize) << 5) | self.rom_bank as usize
+        };
+        rom[rom_offset(rom, bank, address)]
+    }
+    fn ram_bank(&self) -> usize
+    {
+        if self.advanced_banking {self.upper_bank as usize} else {0}
+    }
+    pub fn read_ram(&self, ram: &[u8], address: usize) -> u8
+    {
+        if !self.ram_enabled || ram.is_empty()
+        {
+            return 0xFF;
+        }
+        ram[ram_offset(ram, self.ram_bank(), address)]
+    }
+    pub fn write_ram(&mut self, ram: &mut [u8], address: usize, value: u8)
+    {
+        if self.ram_enabled && !ram.is_empty()
+        {
+            ram[ram_offset(ram, self.ram_bank(), address)] = value;
+        }
+    }
+}
+
+//The RAM is built into the MBC2 itself, 512 half bytes
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+#[derive(Clone)]
+pub struct MBC2
+{
+    ram_enabled: bool,
+    //4 bit register, 0 selects bank 1
+    rom_bank: u8,
+}
+impl MBC2
+{
+    pub fn new() -> MBC2
+    {
+        MBC2
+        {
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+    //Both registers live in 0x0000-0x3FFF, address bit 8 picks which one gets written
+    pub fn write_register(&mut self, address: usize, value: u8)
+    {
+        if address >= GAME_ROM_BANK_N_START
+        {
+            return;
+        }
+        if address & 0x100 == 0
+        {
+            self.ram_enabled = (value & 0x0F) == 0x0A;
+        }
+        else
+        {
+            self.rom_bank = if value & 0x0F == 0 {1} else {value & 0x0F};
+        }
+    }
+    pub fn read_rom(&self, rom: &[u8], address: usize) -> u8
+    {
+        let bank = if address < GAME_ROM_BANK_N_START {0} else {self.rom_bank as usize};
+        rom[rom_offset(rom, bank, address)]
+    }
+    //Only 9 address lines go to the RAM, so the 512 entries repeat all the way through 0xA000-0xBFFF.
+    //There are only 4 data lines as well, the upper half of the byte is open bus and reads as 1s.
+    pub fn read_ram(&self, ram: &[u8], address: usize) -> u8
+    {
+        if !self.ram_enabled
+        {
+            return 0xFF;
+        }
+        ram[(address - CARTRIDGE_RAM_START) % MBC2_RAM_SIZE] | 0xF0
+    }
+    pub fn write_ram(&mut self, ram: &mut [u8], address: usize, value: u8)
+    {
+        if self.ram_enabled
+        {
+            ram[(address - CARTRIDGE_RAM_START) % MBC2_RAM_SIZE] = value & 0x0F;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MBC3
+{
+    //Enables both the RAM and the clock registers
+    ram_enabled: bool,
+    //7 bit register, 0 selects bank 1
+    rom_bank: u8,
+    //0x00-0x03 pick a RAM bank, 0x08-0x0C map a clock register into 0xA000-0xBFFF instead
+    ram_bank: u8,
+    //Last value written to 0x6000-0x7FFF, the clock latches on a 0 followed by a 1
+    latch_register: u8,
+    pub rtc: Option<RealTimeClock>,
+}
+impl MBC3
+{
+    pub fn new(rtc: Option<RealTimeClock>) -> MBC3
+    {
+        MBC3
+        {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            latch_register: 0xFF,
+            rtc,
+        }
+    }
+    pub fn write_register(&mut self, address: usize, value: u8)
+    {
+        match address
+        {
+            RAM_ENABLE_START..=RAM_ENABLE_END => self.ram_enabled = (value & 0x0F) == 0x0A,
+            ROM_BANK_NUMBER_START..=ROM_BANK_NUMBER_END => self.rom_bank = if value & 0x7F == 0 {1} else {value & 0x7F},
+            RAM_BANK_NUMBER_START..=RAM_BANK_NUMBER_END => self.ram_bank = value & 0x0F,
+            BANKING_MODE_START..=BANKING_MODE_END =>
+            {
+                if self.latch_register == 0x00 && value == 0x01
+                {
+                    if let Some(rtc) = &mut self.rtc
+                    {
+                        rtc.latch();
+                    }
+                }
+                self.latch_register = value;
+            },
+            _ => {},
+        }
+    }
+    pub fn read_rom(&self, rom: &[u8], address: usize) -> u8
+    {
+        let bank = if address < GAME_ROM_BANK_N_START {0} else {self.rom_bank as usize};
+        rom[rom_offset(rom, bank, address)]
+    }
+    pub fn read_ram(&self, ram: &[u8], address: usize) -> u8
+    {
+        if !self.ram_enabled
+        {
+            return 0xFF;
+        }
+        match (self.ram_bank, &self.rtc)
+        {
+            (0x00..=0x03, _) if !ram.is_empty() => ram[ram_offset(ram, self.ram_bank as usize, address)],
+            (RTC_SECONDS..=RTC_DAY_HIGH, Some(rtc)) => rtc.read(self.ram_bank),
+            _ => 0xFF,
+        }
+    }
+    pub fn write_ram(&mut self, ram: &mut [u8], address: usize, value: u8)
+    {
+        if !self.ram_enabled
+        {
+            return;
+        }
+        match (self.ram_bank, &mut self.rtc)
+        {
+            (0x00..=0x03, _) if !ram.is_empty() => ram[ram_offset(ram, self.ram_bank as usize, address)] = value,
+            (RTC_SECONDS..=RTC_DAY_HIGH, Some(rtc)) => rtc.write(self.ram_bank, value),
+            _ => {},
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MBC5
+{
+    ram_enabled: bool,
+    //9 bit register split across 0x2000 and 0x3000. Unlike the older controllers bank 0 is allowed.
+    rom_bank: u16,
+    ram_bank: u8,
+    //On rumble boards bit 3 of the RAM bank register drives the motor instead of a RAM address line
+    has_rumble: bool,
+    pub rumble: bool,
+}
+impl MBC5
+{
+    pub fn new(has_rumble: bool) -> MBC5
+    {
+        MBC5
+        {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble: false,
+        }
+    }
+    pub fn write_register(&mut self, address: usize, value: u8)
+    {
+        match address
+        {
+            RAM_ENABLE_START..=RAM_ENABLE_END => self.ram_enabled = (value & 0x0F) == 0x0A,
+            ROM_BANK_NUMBER_START..=ROM_BANK_LOW_END => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            ROM_BANK_HIGH_START..=ROM_BANK_NUMBER_END => self.rom_bank = (self.rom_bank & 0xFF) | (((value & 0x01) as u16) << 8),
+            RAM_BANK_NUMBER_START..=RAM_BANK_NUMBER_END =>
+            {
+                if self.has_rumble
+                {
+                    self.rumble = (value & 0x08) != 0;
+                    self.ram_bank = value & 0x07;
+                }
+                else
+                {
+                    self.ram_bank = value & 0x0F;
+                }
+            },
+            _ => {},
+        }
+    }
+    pub fn read_rom(&self, rom: &[u8], address: usize) -> u8
+    {
+        let bank = if address < GAME_ROM_BANK_N_START {0} else {self.rom_bank as usize};
+        rom[rom_offset(rom, bank, address)]
+    }
+    pub fn read_ram(&self, ram: &[u8], address: usize) -> u8
+    {
+        if !self.ram_enabled || ram.is_empty()
+        {
+            return 0xFF;
+        }
+        ram[ram_offset(ram, self.ram_bank as usize, address)]
+    }
+    pub fn write_ram(&mut self, ram: &mut [u8], address: usize, value: u8)
+    {
+        if self.ram_enabled && !ram.is_empty()
+        {
+            ram[ram_offset(ram, self.ram_bank as usize, address)] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::Cartridge::test_rom;
+    use crate::RTC::RTC_MINUTES;
+
+    const RAM_START: usize = CARTRIDGE_RAM_START;
+
+    fn mapped_bank(mbc: &MBC, rom: &[u8], address: usize) -> usize
+    {
+        u16::from_le_bytes([mbc.read_rom(rom, address), mbc.read_rom(rom, address + 1)]) as usize
+    }
+
+    #[test]
+    fn mbc1_rom_banking()
+    {
+        let rom = test_rom(0x01, 0x06, 0x00);
+        let mut mbc = MBC::MBC1(MBC1::new());
+        assert_eq!(mapped_bank(&mbc, &rom, 0x0000), 0);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 1);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 5);
+        //0 becomes 1, and so do the banks whose low 5 bits are 0
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 1);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 0x21);
+        mbc.write_rom(0x2000, 0x03);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 0x23);
+        //The upper bits only reach 0x0000-0x3FFF in advanced banking mode
+        assert_eq!(mapped_bank(&mbc, &rom, 0x0000), 0);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x0000), 0x20);
+    }
+
+    #[test]
+    fn mbc1_bank_wraps_to_rom_size()
+    {
+        let rom = test_rom(0x01, 0x03, 0x00);
+        let mut mbc = MBC::MBC1(MBC1::new());
+        mbc.write_rom(0x2000, 0x11);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 1);
+    }
+
+    #[test]
+    fn mbc1_ram()
+    {
+        let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+        let mut mbc = MBC::MBC1(MBC1::new());
+        //Disabled RAM ignores writes and reads as open bus
+        mbc.write_ram(&mut ram, RAM_START, 0x12);
+        assert_eq!(mbc.read_ram(&ram, RAM_START), 0xFF);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(&mut ram, RAM_START, 0x12);
+        assert_eq!(mbc.read_ram(&ram, RAM_START), 0x12);
+        //The RAM bank only switches in advanced banking mode
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(&ram, RAM_START), 0x12);
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_ram(&mut ram, RAM_START, 0x34);
+        assert_eq!(ram[2 * RAM_BANK_SIZE], 0x34);
+        assert_eq!(ram[0], 0x12);
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(&ram, RAM_START), 0xFF);
+    }
+
+    #[test]
+    fn mbc3_rom_banking()
+    {
+        let rom = test_rom(0x11, 0x06, 0x00);
+        let mut mbc = MBC::MBC3(MBC3::new(None));
+        mbc.write_rom(0x2000, 0x7F);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 0x7F);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 1);
+    }
+
+    #[test]
+    fn mbc3_clock_registers()
+    {
+        let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+        let mut mbc = MBC::MBC3(MBC3::new(Some(RealTimeClock::new())));
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, RTC_MINUTES);
+        mbc.write_ram(&mut ram, RAM_START, 42);
+        //Reads come from the latched copy, which only updates on 0 then 1 written to 0x6000
+        assert_eq!(mbc.read_ram(&ram, RAM_START) & 0x3F, 0);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(&ram, RAM_START) & 0x3F, 0);
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(&ram, RAM_START) & 0x3F, 42);
+        //Back to RAM, which the clock writes didn't touch
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_ram(&ram, RAM_START), 0);
+        mbc.write_ram(&mut ram, RAM_START, 0x55);
+        assert_eq!(ram[RAM_BANK_SIZE], 0x55);
+    }
+
+    #[test]
+    fn mbc5_rom_banking()
+    {
+        let rom = test_rom(0x19, 0x08, 0x00);
+        let mut mbc = MBC::MBC5(MBC5::new(false));
+        //Bank 0 can be mapped into 0x4000-0x7FFF
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 0);
+        mbc.write_rom(0x2000, 0xFF);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 0x1FF);
+        mbc.write_rom(0x2000, 0x02);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 0x102);
+        mbc.write_rom(0x3000, 0x00);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 0x02);
+    }
+
+    #[test]
+    fn mbc5_ram_banking_and_rumble()
+    {
+        let mut ram = vec![0; 16 * RAM_BANK_SIZE];
+        let mut mbc = MBC::MBC5(MBC5::new(false));
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x0F);
+        mbc.write_ram(&mut ram, RAM_START, 0x12);
+        assert_eq!(ram[15 * RAM_BANK_SIZE], 0x12);
+        assert!(!mbc.rumble());
+
+        //On rumble boards bit 3 drives the motor instead
+        let mut ram = vec![0; 8 * RAM_BANK_SIZE];
+        let mut mbc = MBC::MBC5(MBC5::new(true));
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x0B);
+        assert!(mbc.rumble());
+        mbc.write_ram(&mut ram, RAM_START, 0x34);
+        assert_eq!(ram[3 * RAM_BANK_SIZE], 0x34);
+        mbc.write_rom(0x4000, 0x03);
+        assert!(!mbc.rumble());
+    }
+
+    #[test]
+    fn mbc2_registers_picked_by_address_bit_8()
+    {
+        let rom = test_rom(0x05, 0x03, 0x00);
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        let mut mbc = MBC::MBC2(MBC2::new());
+        //Bit 8 clear is the RAM enable
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(&mut ram, RAM_START, 0x05);
+        assert_eq!(mbc.read_ram(&ram, RAM_START), 0xF5);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 1);
+        //Bit 8 set is the ROM bank
+        mbc.write_rom(0x2100, 0x0F);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 0x0F);
+        mbc.write_rom(0x0100, 0x00);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 1);
+        assert_eq!(mbc.read_ram(&ram, RAM_START), 0xF5);
+        //Nothing above 0x3FFF
+        mbc.write_rom(0x4100, 0x03);
+        assert_eq!(mapped_bank(&mbc, &rom, 0x4000), 1);
+    }
+
+    #[test]
+    fn mbc2_ram_is_512_nibbles()
+    {
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        let mut mbc = MBC::MBC2(MBC2::new());
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(&mut ram, RAM_START + 0x10, 0xAB);
+        assert_eq!(ram[0x10], 0x0B);
+        //Upper half reads as 1s, and the 512 entries repeat through the whole range
+        assert_eq!(mbc.read_ram(&ram, RAM_START + 0x10), 0xFB);
+        assert_eq!(mbc.read_ram(&ram, RAM_START + 0x210), 0xFB);
+        assert_eq!(mbc.read_ram(&ram, 0xBE10), 0xFB);
+    }
+}
@@ -1,29 +1,37 @@
 use crate::
 {
-    InterruptFlags::InterruptFlags,
+    APU,
+    Cartridge::Cartridge,
+    InterruptFlags::{Interrupt, InterruptFlags},
     Joypad,
-    Timer::{Frequency, Timer},
+    Model::Model,
+    Timer,
     PPU
 };
 
 pub struct MemoryBus
 {
     pub boot_rom: [u8; BOOT_ROM_SIZE],
-    pub game_rom_bank_zero: [u8; GAME_ROM_BANK_ZERO_SIZE],
-    pub game_rom_bank_n: [u8; GAME_ROM_BANK_N_SIZE],
-    pub cartridge_ram: [u8; CARTRIDGE_RAM_SIZE],
+    pub cartridge: Cartridge,
     pub working_ram: [u8; WORKING_RAM_SIZE],
-    pub echo_ram: [u8; ECHO_RAM_SIZE],
     pub ppu: PPU::PPU,
     pub object_attribute_memory: [u8; OBJECT_ATTRIBUTE_MEMORY_SIZE],
     pub unused_mem: [u8; UNUSED_MEMORY_SIZE],
     pub joypad: Joypad::Joypad,
+    pub serial_data: u8,
+    pub serial_control: u8,
+    pub apu: APU::APU,
     pub high_ram: [u8; HIGH_RAM_SIZE],
     pub interrupt_register: InterruptFlags,
     pub interrupt_flag: InterruptFlags,
     pub boot_rom_enabled: bool,
-    pub timer: Timer,
-    pub divider: Timer
+    //OAM DMA: the last value written to 0xFF46, where the transfer reads from, and how many bytes it has
+    //copied (None when no transfer is running)
+    pub dma_register: u8,
+    dma_source: usize,
+    dma_progress: Option<usize>,
+    dma_cycles: u8,
+    pub timer: Timer::Timer
 }
 
 pub const BOOT_ROM_START: usize = 0x0000;
@@ -66,6 +74,9 @@ pub const IO_REGISTERS_START: usize = 0xFF00;
 pub const IO_REGISTERS_END: usize = 0xFF7F;
 pub const IO_REGISTERS_SIZE: usize = IO_REGISTERS_END - IO_REGISTERS_START + 1;
 
+pub const DMA: usize = 0xFF46;
+pub const BOOT_ROM_DISABLE: usize = 0xFF50;
+
 pub const HIGH_RAM_START: usize = 0xFF80;
 pub const HIGH_RAM_END: usize = 0xFFFE;
 pub const HIGH_RAM_SIZE: usize = HIGH_RAM_END - HIGH_RAM_START + 1;
@@ -74,49 +85,59 @@ pub const INTERRUPT_REGISTER: usize = 0xFFFF;
 
 impl MemoryBus
 {
-    pub fn new(boot_rom_buffer: Vec<u8>, game_rom_buffer: Vec<u8>) -> Self
+    //A boot ROM has to be exactly BOOT_ROM_SIZE bytes. GameBoy::new checks that and reports it as an error, so
+    //anything building a bus directly has to check it too.
+    pub fn new(boot_rom_buffer: Option<Vec<u8>>, cartridge: Cartridge) -> Self
     {
-        let boot_rom = {if boot_rom_buffer.len() != BOOT_ROM_SIZE
+        let mut boot_rom = [0; BOOT_ROM_SIZE];
+        if let Some(boot_rom_buffer) = &boot_rom_buffer
         {
-            panic!("INVALID BOOT ROM.");
+            debug_assert_eq!(boot_rom_buffer.len(), BOOT_ROM_SIZE, "boot ROM size should have been checked");
+            boot_rom.copy_from_slice(boot_rom_buffer);
         }
-        else 
-        {
-            let mut boot_rom = [0; BOOT_ROM_SIZE];
-            boot_rom.copy_from_slice(&boot_rom_buffer);
-            boot_rom
-        }};
 
-        let mut game_rom_bank_zero = [0; GAME_ROM_BANK_ZERO_SIZE];
-        for i in 0..GAME_ROM_BANK_ZERO_SIZE
-        {
-            game_rom_bank_zero[i] = game_rom_buffer[i];
-        }
-        let mut game_rom_bank_n = [0; GAME_ROM_BANK_N_SIZE];
-        for i in 0..GAME_ROM_BANK_N_SIZE
-        {
-            game_rom_bank_n[i] = game_rom_buffer[GAME_ROM_BANK_N_SIZE + i];
-        }
-        let mut divider = Timer::new(crate::Timer::Frequency::F16384);
-        divider.enabled = true;
         Self
         {
             boot_rom,
-            game_rom_bank_zero,
-            game_rom_bank_n,
+            cartridge,
             ppu: PPU::PPU::new(),
-            cartridge_ram: [0; CARTRIDGE_RAM_SIZE],
             working_ram: [0; WORKING_RAM_SIZE],
-            echo_ram: [0; ECHO_RAM_SIZE],
             object_attribute_memory: [0; OBJECT_ATTRIBUTE_MEMORY_SIZE],
             joypad: Joypad::Joypad::new(),
+            serial_data: 0,
+            serial_control: 0,
+            apu: APU::APU::new(),
             unused_mem: [0; UNUSED_MEMORY_SIZE],
             high_ram: [0; HIGH_RAM_SIZE],
             interrupt_register: InterruptFlags::new(),
             interrupt_flag: InterruptFlags::new(),
-            boot_rom_enabled: true,
-            timer: Timer::new(crate::Timer::Frequency::F4096),
-            divider
+            boot_rom_enabled: boot_rom_buffer.is_some(),
+            dma_register: 0xFF,
+            dma_source: 0,
+            dma_progress: None,
+            dma_cycles: 0,
+            timer: Timer::Timer::new()
+        }
+    }
+
+    //Advance everything hanging off the bus by the cycles the CPU just spent, collecting their interrupt requests in IF
+    pub fn step(&mut self, cycles: u8)
+    {
+        self.step_dma(cycles);
+        self.ppu.step(cycles, &mut self.interrupt_flag);
+        self.cartridge.step(cycles);
+        if self.timer.step(cycles)
+        {
+            self.interrupt_flag.request(Interrupt::Timer);
+        }
+        self.apu.step(cycles, self.timer.divider());
+    }
+
+    pub fn set_buttons(&mut self, buttons: Joypad::Buttons)
+    {
+        if self.joypad.set_buttons(buttons)
+        {
+            self.interrupt_flag.request(Interrupt::Joypad);
         }
     }
 
@@ -125,29 +146,85 @@ impl MemoryBus
         self.boot_rom_enabled = false;
     }
 
+    //Leave the IO registers how the boot ROM would have
+    pub fn skip_boot_rom(&mut self, model: Model)
+    {
+        self.boot_rom_enabled = false;
+        for (address, value) in model.io_registers()
+        {
+            self.write_byte(address, value);
+        }
+        //Writing DIV only ever resets it
+        self.timer.counter = (model.divider() as u16) << 8;
+    }
+
+    pub fn dma_active(&self) -> bool
+    {
+        self.dma_progress.is_some()
+    }
+
+    fn start_dma(&mut self, value: u8)
+    {
+        self.dma_register = value;
+        //Sources past 0xDFFF land in WRAM, the same way echo RAM does (0xFE00 and 0xFF00 included)
+        let page = if value >= 0xE0 {value - 0x20} else {value};
+        self.dma_source = (page as usize) << 8;
+        //Writing again mid transfer starts over from the new source
+        self.dma_progress = Some(0);
+        self.dma_cycles = 0;
+    }
+
+    //One byte per M-cycle, 160 M-cycles for the whole of OAM
+    fn step_dma(&mut self, cycles: u8)
+    {
+        let Some(mut progress) = self.dma_progress else
+        {
+            return;
+        };
+        self.dma_cycles += cycles;
+        while self.dma_cycles >= 4 && progress < OBJECT_ATTRIBUTE_MEMORY_SIZE
+        {
+            self.dma_cycles -= 4;
+            let value = self.read_mapped(self.dma_source + progress);
+            self.ppu.write_oam(progress, value);
+            progress += 1;
+        }
+        self.dma_progress = if progress < OBJECT_ATTRIBUTE_MEMORY_SIZE {Some(progress)} else {None};
+    }
+
+    //While a DMA transfer owns the main bus the CPU can only get at 0xFF00-0xFFFF (IO, HRAM and IE), which sit on
+    //their own internal bus. Anything else reads as 0xFF.
+    fn blocked_by_dma(&self, address: usize) -> bool
+    {
+        self.dma_active() && address < IO_REGISTERS_START
+    }
+
     pub fn read_byte(&mut self, address: u16) -> u8
     {
         let address = address as usize;
+        if self.blocked_by_dma(address)
+        {
+            return 0xFF;
+        }
+        self.read_mapped(address)
+    }
+
+    fn read_mapped(&mut self, address: usize) -> u8
+    {
         match address
         {
-            BOOT_ROM_START..=BOOT_ROM_END =>
-            {
-                if self.boot_rom_enabled
-                {self.boot_rom[address]}
-                else
-                {self.game_rom_bank_zero[address]}
-            }
-            GAME_ROM_BANK_ZERO_START..=GAME_ROM_BANK_ZERO_END => self.game_rom_bank_zero[address],
-            GAME_ROM_BANK_N_START..=GAME_ROM_BANK_N_END => self.game_rom_bank_n[address - GAME_ROM_BANK_N_START],
-            VRAM_START..=VRAM_END => self.ppu.read_from_vram(address),
-            CARTRIDGE_RAM_START..=CARTRIDGE_RAM_END => self.cartridge_ram[address - CARTRIDGE_RAM_START],
+            BOOT_ROM_START..=BOOT_ROM_END if self.boot_rom_enabled => self.boot_rom[address],
+            GAME_ROM_BANK_ZERO_START..=GAME_ROM_BANK_N_END => self.cartridge.read_rom(address),
+            VRAM_START..=VRAM_END => self.ppu.read_from_vram(address - VRAM_START),
+            CARTRIDGE_RAM_START..=CARTRIDGE_RAM_END => self.cartridge.read_ram(address),
             WORKING_RAM_START..=WORKING_RAM_END => self.working_ram[address - WORKING_RAM_START],
-            ECHO_RAM_START..=ECHO_RAM_END => self.echo_ram[address - ECHO_RAM_START],
+            //Echo RAM is WRAM again, the top address line isn't decoded
+            ECHO_RAM_START..=ECHO_RAM_END => self.working_ram[address - ECHO_RAM_START],
             OBJECT_ATTRIBUTE_MEMORY_START..=OBJECT_ATTRIBUTE_MEMORY_END => self.ppu.read_oam(address - OBJECT_ATTRIBUTE_MEMORY_START),
             UNUSED_MEMORY_START..=UNUSED_MEMORY_END => 0,
             IO_REGISTERS_START..=IO_REGISTERS_END => self.read_io_registers(address),
             HIGH_RAM_START..=HIGH_RAM_END => self.high_ram[address - HIGH_RAM_START],
-            INTERRUPT_REGISTER => self.interrupt_register.to_byte(),
+            INTERRUPT_REGISTER => 0xE0 | self.interrupt_register.to_byte(),
             _ => {panic!("UNKNOWN ADDRESS 0x{:x}", address)}
         }
     }
@@ -155,24 +232,26 @@ impl MemoryBus
     pub fn read_word(&mut self, address: u16) -> u16
     {
         let lo = self.read_byte(address) as u16;
-        let hi = (self.read_byte(address + 1) << 8) as u16;
-        let word = lo | hi;
-        word
+        let hi = (self.read_byte(address + 1) as u16) << 8;
+        lo | hi
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8)
     {
         let address = address as usize;
+        if self.blocked_by_dma(address)
+        {
+            return;
+        }
         match address
         {
-            GAME_ROM_BANK_ZERO_START..=GAME_ROM_BANK_ZERO_END => {self.game_rom_bank_zero[address] = value;},
-            GAME_ROM_BANK_N_START..=GAME_ROM_BANK_N_END => {self.game_rom_bank_n[address - GAME_ROM_BANK_N_START] = value;},
-            VRAM_START..=VRAM_END => {self.ppu.write_to_vram(address, value);},
-            CARTRIDGE_RAM_START..=CARTRIDGE_RAM_END => {self.cartridge_ram[address - CARTRIDGE_RAM_START] = value;},
+            GAME_ROM_BANK_ZERO_START..=GAME_ROM_BANK_N_END => {self.cartridge.write_rom(address, value);},
+            VRAM_START..=VRAM_END => {self.ppu.write_to_vram(address - VRAM_START, value);},
+            CARTRIDGE_RAM_START..=CARTRIDGE_RAM_END => {self.cartridge.write_ram(address, value);},
             WORKING_RAM_START..=WORKING_RAM_END => {self.working_ram[address - WORKING_RAM_START] = value;},
-            ECHO_RAM_START..=ECHO_RAM_END => {self.echo_ram[address - ECHO_RAM_START] = value;},
-            OBJECT_ATTRIBUTE_MEMORY_START..=OBJECT_ATTRIBUTE_MEMORY_END => {self.ppu.write_oam(address, value);},
-            UNUSED_MEMORY_START..=UNUSED_MEMORY_END => {panic!("ATTEMPT TO WRITE TO UNUSED MEMORY ADDRESS 0x{:x}", address);},
+            ECHO_RAM_START..=ECHO_RAM_END => {self.working_ram[address - ECHO_RAM_START] = value;},
+            OBJECT_ATTRIBUTE_MEMORY_START..=OBJECT_ATTRIBUTE_MEMORY_END => {self.ppu.write_oam(address - OBJECT_ATTRIBUTE_MEMORY_START, value);},
+            UNUSED_MEMORY_START..=UNUSED_MEMORY_END => {},
             IO_REGISTERS_START..=IO_REGISTERS_END => {self.write_io_registers(address, value)},
             HIGH_RAM_START..=HIGH_RAM_END => {self.high_ram[address - HIGH_RAM_START] = value;},
             INTERRUPT_REGISTER => {self.interrupt_register.from_byte(value);},
@@ -188,36 +267,174 @@ impl MemoryBus
         self.write_byte(address + 1, hi);
     }
 
+    //Anything without a register behind it reads as open bus, 0xFF
     pub fn read_io_registers(&mut self, address: usize) -> u8
     {
         match address
         {
             0xFF00 => {self.joypad.into()},
-            0xFF01 => {/*SERIAL TRANSFER DATA*/0},
-            0xFF02 => {/*SERIAL TRANSFER CONTROL*/0},
-            0xFF04 => {self.divider.value},
-            0xFF05 => {self.timer.value},
-            0xFF06 => {self.timer.modulo},
-            0xFF07 => {let clock: u8 = match self.timer.frequency
-                        {
-                            Frequency::F4096 => {0b00},
-                            Frequency::F16384 => {0b11},
-                            Frequency::F65536 => {0b10},
-                            Frequency::F262144 => {0b01},
-                        };
-                        (self.timer.enabled as u8) << 2 | clock
-                        },
-            0xFF0F => {self.interrupt_flag.to_byte()}
-            _      => panic!("HELP")
+            0xFF01 => {self.serial_data},
+            0xFF02 => {0x7E | self.serial_control},
+            Timer::DIV..=Timer::TAC => {self.timer.read(address)},
+            //Only the low 5 bits of IF exist, the rest read as 1
+            0xFF0F => {0xE0 | self.interrupt_flag.to_byte()},
+            DMA => {self.dma_register},
+            APU::SOUND_REGISTERS_START..=APU::WAVE_RAM_END => {self.apu.read(address)},
+            PPU::LCDC..=PPU::LYC | PPU::BGP..=PPU::WX => {self.ppu.read_register(address)},
+            _      => 0xFF,
         }
     }
 
+    //Writes to registers that don't exist are dropped
     pub fn write_io_registers(&mut self, address: usize, value: u8)
     {
         match address
         {
-            0xFF00 => {self.joypad = value.into();},
-            _      => panic!("HELP"),
+            0xFF00 => {let pressed = self.joypad.select(value);
+                        if pressed
+                        {
+                            self.interrupt_flag.request(Interrupt::Joypad);
+                        }
+                        },
+            0xFF01 => {self.serial_data = value;},
+            0xFF02 => {self.serial_control = value & 0x81;},
+            Timer::DIV..=Timer::TAC => {self.timer.write(address, value);},
+            0xFF0F => {self.interrupt_flag.from_byte(value);},
+            DMA => {self.start_dma(value);},
+            APU::SOUND_REGISTERS_START..=APU::WAVE_RAM_END => {self.apu.write(address, value);},
+            PPU::LCDC..=PPU::LYC | PPU::BGP..=PPU::WX => {self.ppu.write_register(address, value);},
+            //Once it's off the boot ROM stays off until power cycle
+            BOOT_ROM_DISABLE if value != 0 => {self.disable_boot_rom();},
+            _      => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::Cartridge::test_rom;
+
+    #[test]
+    fn unused_io_bits_read_as_1()
+    {
+        let mut bus = MemoryBus::new(None, Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap());
+        bus.skip_boot_rom(Model::DMG);
+        //Just the VBlank request the boot ROM leaves behind
+        assert_eq!(bus.read_byte(0xFF0F), 0xE1);
+        bus.write_byte(0xFF0F, 0x00);
+        assert_eq!(bus.read_byte(0xFF0F), 0xE0);
+        bus.write_byte(0xFF00, 0x30);
+        assert_eq!(bus.read_byte(0xFF00), 0xFF);
+        bus.write_byte(0xFF02, 0x00);
+        assert_eq!(bus.read_byte(0xFF02), 0x7E);
+        bus.write_byte(Timer::TAC as u16, 0x00);
+        assert_eq!(bus.read_byte(Timer::TAC as u16), 0xF8);
+        bus.write_byte(PPU::STAT as u16, 0x00);
+        assert_eq!(bus.read_byte(PPU::STAT as u16) & 0x80, 0x80);
+        bus.write_byte(APU::NR10 as u16, 0x00);
+        assert_eq!(bus.read_byte(APU::NR10 as u16), 0x80);
+    }
+
+    #[test]
+    fn unmapped_io_reads_as_open_bus()
+    {
+        let mut bus = MemoryBus::new(None, Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap());
+        for address in [0xFF03, 0xFF08, 0xFF0E, 0xFF15, 0xFF1F, 0xFF27, 0xFF2F, 0xFF4C, 0xFF50, 0xFF7F]
+        {
+            bus.write_byte(address, 0x00);
+            assert_eq!(bus.read_byte(address), 0xFF, "0x{:04X}", address);
+        }
+    }
+
+    #[test]
+    fn ppu_and_timer_interrupts_reach_if()
+    {
+        let mut bus = MemoryBus::new(None, Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap());
+        bus.skip_boot_rom(Model::DMG);
+        bus.write_byte(0xFF0F, 0x00);
+        //Timer at 262144 Hz, one tick from overflowing
+        bus.write_byte(Timer::TAC as u16, 0b101);
+        bus.write_byte(Timer::TIMA as u16, 0xFF);
+        for _ in 0..8
+        {
+            bus.step(4);
+        }
+        assert_eq!(bus.read_byte(0xFF0F), 0xE4);
+        bus.write_byte(Timer::TAC as u16, 0x00);
+        bus.write_byte(0xFF0F, 0x00);
+        //A frame always gets as far as VBlank
+        for _ in 0..crate::CYCLES_PER_FRAME / 4
+        {
+            bus.step(4);
         }
+        assert_eq!(bus.read_byte(0xFF0F) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn joypad_interrupt_reaches_if()
+    {
+        let mut bus = MemoryBus::new(None, Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap());
+        bus.write_byte(0xFF00, 0x10);
+        //A button the CPU isn't looking at doesn't pull a line low
+        bus.set_buttons(Joypad::Buttons { up: true, ..Default::default() });
+        assert_eq!(bus.read_byte(0xFF0F), 0xE0);
+        bus.set_buttons(Joypad::Buttons { a: true, ..Default::default() });
+        assert_eq!(bus.read_byte(0xFF0F), 0xF0);
+        //Selecting a group with a button already held fires it too
+        bus.write_byte(0xFF0F, 0x00);
+        bus.set_buttons(Joypad::Buttons { up: true, ..Default::default() });
+        bus.write_byte(0xFF00, 0x20);
+        assert_eq!(bus.read_byte(0xFF0F), 0xF0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn dma_copies_oam_over_160_m_cycles()
+    {
+        let mut bus = MemoryBus::new(None, Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap());
+        for offset in 0..OBJECT_ATTRIBUTE_MEMORY_SIZE
+        {
+            bus.write_byte((0xC100 + offset) as u16, offset as u8 + 1);
+        }
+        //0xE1 is past the end of WRAM, so it reads the mirror at 0xC100
+        bus.write_byte(DMA as u16, 0xE1);
+        assert_eq!(bus.read_byte(DMA as u16), 0xE1);
+        for _ in 0..159
+        {
+            bus.step(4);
+        }
+        assert!(bus.dma_active());
+        assert_eq!(bus.ppu.read_oam(158), 159);
+        assert_eq!(bus.ppu.read_oam(159), 0);
+        bus.step(4);
+        assert!(!bus.dma_active());
+        for offset in 0..OBJECT_ATTRIBUTE_MEMORY_SIZE
+        {
+            //OAM only keeps the attribute bits the DMG uses
+            let mask = if offset % 4 == 3 {0xF0} else {0xFF};
+            assert_eq!(bus.ppu.read_oam(offset), (offset as u8 + 1) & mask, "{}", offset);
+        }
+    }
+
+    #[test]
+    fn dma_leaves_only_high_ram_and_io_reachable()
+    {
+        let mut bus = MemoryBus::new(None, Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).unwrap());
+        bus.write_byte(0xC000, 0x12);
+        bus.write_byte(HIGH_RAM_START as u16, 0x34);
+        bus.write_byte(DMA as u16, 0xC0);
+        bus.step(4);
+        assert_eq!(bus.read_byte(0xC000), 0xFF);
+        assert_eq!(bus.read_byte(0x0000), 0xFF);
+        assert_eq!(bus.read_byte(HIGH_RAM_START as u16), 0x34);
+        assert_eq!(bus.read_byte(0xFF0F), 0xE0);
+        //Writes outside them are lost too
+        bus.write_byte(0xC000, 0x56);
+        for _ in 0..160
+        {
+            bus.step(4);
+        }
+        assert_eq!(bus.read_byte(0xC000), 0x12);
+    }
+}
@@ -0,0 +1,67 @@
+//Which Game Boy we're emulating. When a boot ROM is run it sets all of this up itself, it only matters
+//when starting straight from the cartridge.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Model
+{
+    //Early Japanese units with the first revision of the boot ROM
+    DMG0,
+    DMG,
+    //Game Boy Pocket
+    MGB,
+    SGB,
+    SGB2,
+}
+impl Model
+{
+    pub fn from_name(name: &str) -> Option<Model>
+    {
+        match name.to_ascii_lowercase().as_str()
+        {
+            "dmg0" => Some(Model::DMG0),
+            "dmg" => Some(Model::DMG),
+            "mgb" => Some(Model::MGB),
+            "sgb" => Some(Model::SGB),
+            "sgb2" => Some(Model::SGB2),
+            _ => None,
+        }
+    }
+    //A, F, B, C, D, E, H, L as the boot ROM leaves them. The DMG and MGB boot ROMs finish on the header
+    //checksum loop, so H and C depend on the cartridge.
+    pub fn registers(&self, header_checksum: u8) -> [u8; 8]
+    {
+        let checksum_flags = if header_checksum == 0 {0x80} else {0xB0};
+        match self
+        {
+            Model::DMG0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            Model::DMG => [0x01, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::MGB => [0xFF, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::SGB => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::SGB2 => [0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+        }
+    }
+    //DIV depends on how long the boot ROM took. The SGB ones wait on the SNES so it varies, 0xAB is as good as any.
+    pub fn divider(&self) -> u8
+    {
+        match self
+        {
+            Model::DMG0 => 0x18,
+            _ => 0xAB,
+        }
+    }
+    //IO register writes that recreate the state the boot ROM hands over in (LCDC=0x91, BGP=0xFC...).
+    //These are the values written, not what reads back, so write only bits don't trigger anything extra.
+    //Anything not listed is still at its power on value.
+    pub fn io_registers(&self) -> Vec<(u16, u8)>
+    {
+        //NR52 first, the other sound registers ignore writes while it's off
+        let mut writes = vec![(0xFF26, 0x80), (0xFF11, 0x80), (0xFF12, 0xF3), (0xFF24, 0x77), (0xFF25, 0xF3)];
+        //The tail end of the boot chime is still ringing on channel 1 when the game starts. The SGB boot ROM doesn't play it.
+        if !matches!(self, Model::SGB | Model::SGB2)
+        {
+            writes.extend([(0xFF13, 0xC1), (0xFF14, 0x87)]);
+        }
+        //Both joypad groups selected, and a VBlank request the boot ROM never enabled interrupts to service
+        writes.extend([(0xFF00, 0x00), (0xFF0F, 0x01), (0xFF40, 0x91), (0xFF47, 0xFC)]);
+        writes
+    }
+}
@@ -0,0 +1,160 @@
+use GB_Emulator::APU::{Channel, APU, NR10, NR50};
+
+pub const WIDTH: usize = 512;
+const ROW_HEIGHT: usize = 72;
+//Each row starts with its label, the waveform goes underneath
+const LABEL_HEIGHT: usize = 16;
+//Room under the four waveforms for NR50-NR52
+const FOOTER_HEIGHT: usize = 16;
+pub const HEIGHT: usize = ROW_HEIGHT * 4 + FOOTER_HEIGHT;
+
+const BACKGROUND: u32 = 0x00101010;
+const AXIS: u32 = 0x00303030;
+const TEXT: u32 = 0x00C0C0C0;
+const COLOURS: [u32; 4] = [0x0040E040, 0x0040C0F0, 0x00F0C040, 0x00F06060];
+const MUTED_COLOUR: u32 = 0x00505050;
+
+//Glyphs are 3x5, drawn at twice that
+const GLYPH_SCALE: usize = 2;
+const GLYPH_ADVANCE: usize = 4 * GLYPH_SCALE;
+
+//Only what the labels need: hex digits and the letters in NR, MUTE and SOLO
+fn glyph(character: char) -> [u8; 5]
+{
+    match character
+    {
+        '0' | 'O' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' | 'S' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        _ => [0; 5],
+    }
+}
+
+//Scrolling waveforms of each channel's DAC output with its NRx registers, for seeing what a sound driver is doing
+pub struct Oscilloscope
+{
+    buffer: Vec<u32>,
+    //The last WIDTH samples, oldest first
+    history: Vec<[f32; 4]>,
+}
+impl Oscilloscope
+{
+    pub fn new() -> Oscilloscope
+    {
+        Oscilloscope
+        {
+            buffer: vec![BACKGROUND; WIDTH * HEIGHT],
+            history: vec![[0.0; 4]; WIDTH],
+        }
+    }
+    pub fn buffer(&self) -> &[u32]
+    {
+        &self.buffer
+    }
+    //Pull in whatever the APU has sampled since last time and redraw
+    pub fn update(&mut self, apu: &mut APU)
+    {
+        let samples = apu.take_scope_samples();
+        self.history.extend(samples);
+        let excess = self.history.len().saturating_sub(WIDTH);
+        self.history.drain(..excess);
+
+        self.buffer.fill(BACKGROUND);
+        let registers = apu.registers();
+        for (row, channel) in Channel::ALL.into_iter().enumerate()
+        {
+            let top = row * ROW_HEIGHT;
+            let centre = top + LABEL_HEIGHT + (ROW_HEIGHT - LABEL_HEIGHT) / 2;
+            let colour = if apu.audible(channel) {COLOURS[row]} else {MUTED_COLOUR};
+            self.buffer[centre * WIDTH..(centre + 1) * WIDTH].fill(AXIS);
+
+            let amplitude = ((ROW_HEIGHT - LABEL_HEIGHT) / 2 - 4) as f32;
+            let mut previous = None;
+            for (x, sample) in self.history.iter().enumerate()
+            {
+                let y = (centre as f32 - sample[row] * amplitude) as usize;
+                let (from, to) = match previous
+                {
+                    Some(previous) if previous < y => (previous, y),
+                    Some(previous) => (y, previous),
+                    None => (y, y),
+                };
+                for y in from..=to
+                {
+                    self.buffer[y * WIDTH + x] = colour;
+                }
+                previous = Some(y);
+            }
+
+            //NRx0-NRx4 for this channel
+            let first = NR10 + row * 5;
+            let mut label = format!("NR{}0", row + 1);
+            for register in &registers[first - NR10..first - NR10 + 5]
+            {
+                label += &format!(" {:02X}", register);
+            }
+            if apu.soloed(channel)
+            {
+                label += " SOLO";
+            }
+            else if apu.muted(channel)
+            {
+                label += " MUTE";
+            }
+            self.draw_text(&label, 4, top + 4);
+        }
+        let footer = registers[NR50 - NR10..].iter().enumerate()
+            .map(|(index, register)| format!("NR5{} {:02X}", index, register))
+            .collect::<Vec<_>>()
+            .join("  ");
+        self.draw_text(&footer, 4, ROW_HEIGHT * 4 + 3);
+    }
+    fn draw_text(&mut self, text: &str, x: usize, y: usize)
+    {
+        for (index, character) in text.chars().enumerate()
+        {
+            let left = x + index * GLYPH_ADVANCE;
+            for (row, bits) in glyph(character).iter().enumerate()
+            {
+                for column in 0..3
+                {
+                    if bits & (0b100 >> column) == 0
+                    {
+                        continue;
+                    }
+                    for dy in 0..GLYPH_SCALE
+                    {
+                        for dx in 0..GLYPH_SCALE
+                        {
+                            let px = left + column * GLYPH_SCALE + dx;
+                            let py = y + row * GLYPH_SCALE + dy;
+                            if px < WIDTH && py < HEIGHT
+                            {
+                                self.buffer[py * WIDTH + px] = TEXT;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
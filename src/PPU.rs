@@ -1,10 +1,44 @@
-pub const VRAM_SIZE: usize = 0x1800;
+use std::collections::VecDeque;
+
+use crate::InterruptFlags::{Interrupt, InterruptFlags};
+
+pub const VRAM_SIZE: usize = 0x2000;
 pub const TILE_COUNT: usize = 384;
+//16 bytes a tile, the tile maps follow straight after
+pub const TILE_DATA_SIZE: usize = TILE_COUNT * 16;
+const TILE_MAP_9800: usize = 0x1800;
+const TILE_MAP_9C00: usize = 0x1C00;
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
 pub const NUMBER_OF_OBJECTS: usize = 40;
 pub const OBJECT_ATTRIBUTE_MEMORY_SIZE: usize = 0xA0;
+pub const OBJECTS_PER_LINE: usize = 10;
+
+//Dot timings for a line. Pixel transfer is only fixed with the scanline renderer, HBlank is whatever's left of the 456.
+const OAM_SCAN_CYCLES: u16 = 80;
+const PIXEL_TRANSFER_CYCLES: u16 = 172;
+const LINE_CYCLES: u16 = 456;
+//An object fetch stalls the background fetcher and the pixel output for at least this long
+const OBJECT_FETCH_CYCLES: u8 = 6;
+//The fetcher's first tile of each line is fetched and thrown away
+const FIFO_STARTUP_CYCLES: u8 = 6;
+//What the LCD shows while it's switched off
+const BLANK: u32 = 0x00FFFFFF;
 
+//LCD registers. 0xFF46 (OAM DMA) sits in the middle but belongs to the bus.
+pub const LCDC: usize = 0xFF40;
+pub const STAT: usize = 0xFF41;
+pub const SCY: usize = 0xFF42;
+pub const SCX: usize = 0xFF43;
+pub const LY: usize = 0xFF44;
+pub const LYC: usize = 0xFF45;
+pub const BGP: usize = 0xFF47;
+pub const OBP0: usize = 0xFF48;
+pub const OBP1: usize = 0xFF49;
+pub const WY: usize = 0xFF4A;
+pub const WX: usize = 0xFF4B;
+
+#[derive(Clone, Copy)]
 pub enum Colour
 {
     White = 255,
@@ -26,6 +60,20 @@ impl std::convert::From<u8> for Colour
         }
     }
 }
+impl std::convert::From<&Colour> for u8
+{
+    fn from(colour: &Colour) -> u8
+    {
+        match colour
+        {
+            Colour::White => 0,
+            Colour::LightGray => 1,
+            Colour::DarkGray => 2,
+            Colour::Black => 3,
+        }
+    }
+}
+#[derive(Clone, Copy)]
 pub struct Palette(Colour, Colour, Colour, Colour);
 
 impl Palette
@@ -40,6 +88,18 @@ impl Palette
             Colour::Black,
         )
     }
+    //Shade for a tile pixel as 0RGB
+    fn rgb(&self, value: TilePixelValue) -> u32
+    {
+        let shade = match value
+        {
+            TilePixelValue::Zero => self.0,
+            TilePixelValue::One => self.1,
+            TilePixelValue::Two => self.2,
+            TilePixelValue::Three => self.3,
+        } as u32;
+        (shade << 16) | (shade << 8) | shade
+    }
 }
 //Palette can be altered, so this must be implemented. Also why above is 4 colours as opposed to using Colour again.
 impl std::convert::From<u8> for Palette
@@ -55,7 +115,14 @@ impl std::convert::From<u8> for Palette
         )
     }
 }
-#[derive(Clone, Copy)]
+impl std::convert::From<&Palette> for u8
+{
+    fn from(palette: &Palette) -> u8
+    {
+        u8::from(&palette.0) | u8::from(&palette.1) << 2 | u8::from(&palette.2) << 4 | u8::from(&palette.3) << 6
+    }
+}
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum TilePixelValue
 {
     Zero,
@@ -94,7 +161,6 @@ struct Object
 {
     x: u8,
     y: u8,
-    tile: u8,
     x_flip: bool,
     y_flip: bool,
     pallette: ObjectPalette,
@@ -109,7 +175,6 @@ impl Object
         {
             x: 0,
             y: 0,
-            tile: 0,
             x_flip: false,
             y_flip: false,
             pallette: ObjectPalette::Zero,
@@ -118,24 +183,81 @@ impl Object
         }
     }
 }
-#[derive(Eq, PartialEq)]
-pub enum Interrupts
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Renderer
 {
-    None,
-    VBlank,
-    LCD,
-    Both,
+    //Draws the whole line at the end of pixel transfer, which always takes 172 cycles. Fast, and right for most games.
+    Scanline,
+    //Pushes pixels out one dot at a time through the background and object FIFOs. Pixel transfer stretches with
+    //scrolling, the window and objects the way it does on hardware, and registers written mid line take effect
+    //where the picture has got to.
+    PixelFifo,
 }
-impl Interrupts
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FetcherStep
 {
-    pub fn add(&mut self, interr: Interrupts)
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+#[derive(Clone, Copy)]
+struct ObjectPixel
+{
+    value: TilePixelValue,
+    palette: ObjectPalette,
+    priority: bool,
+}
+//State of the pixel FIFO renderer for the line being drawn
+struct PixelFifo
+{
+    background: VecDeque<TilePixelValue>,
+    objects: VecDeque<ObjectPixel>,
+    step: FetcherStep,
+    //Every step but the push takes 2 dots
+    step_cycles: u8,
+    startup: u8,
+    //Tile column the fetcher is on, counted from the scroll position or the window's left edge
+    tile_x: u8,
+    tile: usize,
+    tile_row: usize,
+    //Fine scroll, pixels thrown away before the first one reaches the LCD
+    discard: u8,
+    //Pixels sent to the LCD so far
+    x: usize,
+    //Dots spent in pixel transfer so far
+    cycles: u16,
+    //Where the window starts this line (WX), if it's showing at all, and whether the fetcher has switched to it
+    window_x: Option<usize>,
+    window: bool,
+    //Next of the line's objects to wait for, and how long is left on the one being fetched
+    next_object: usize,
+    object_fetch: Option<u8>,
+    //Tile the last object fetch waited on the fetcher in, the objects after it in that tile don't wait again
+    object_tile: Option<usize>,
+}
+impl PixelFifo
+{
+    fn new() -> PixelFifo
     {
-        match self
+        PixelFifo
         {
-            Interrupts::LCD => if interr == Interrupts::VBlank {*self = Interrupts::Both},
-            Interrupts::VBlank => if interr == Interrupts::LCD {*self = Interrupts::Both},
-            Interrupts::None => *self = interr,
-            _ => {},
+            background: VecDeque::with_capacity(16),
+            objects: VecDeque::with_capacity(8),
+            step: FetcherStep::Tile,
+            step_cycles: 0,
+            startup: FIFO_STARTUP_CYCLES,
+            tile_x: 0,
+            tile: 0,
+            tile_row: 0,
+            discard: 0,
+            x: 0,
+            cycles: 0,
+            window_x: None,
+            window: false,
+            next_object: 0,
+            object_fetch: None,
+            object_tile: None,
         }
     }
 }
@@ -143,9 +265,20 @@ pub struct PPU
 {
     vram: [u8; VRAM_SIZE],
     oam: [Object; NUMBER_OF_OBJECTS],
+    //OAM indices of the objects picked for this line during OAM scan, in drawing priority order
+    line_objects: [usize; OBJECTS_PER_LINE],
+    line_object_count: usize,
     tiles: [Tile; TILE_COUNT],
     cycles: u16,
     mode: PPUModes,
+    renderer: Renderer,
+    //What's drawing the current line. set_renderer's choice is swapped in when OAM scan finishes so a line is
+    //never drawn half by each.
+    line_renderer: Renderer,
+    fifo: PixelFifo,
+    //How long pixel transfer took on this line, HBlank makes up the rest
+    pixel_transfer_cycles: u16,
+    bgp: Palette,
     obp0: Palette,
     obp1: Palette,
     lcd_enabled: bool,
@@ -159,6 +292,9 @@ pub struct PPU
     ly: u8,
     lyc: u8,
     ly_is_lyc: bool,
+    //State of the one line all the STAT sources share, and an interrupt from the STAT write bug waiting to go out
+    stat_interrupt_line: bool,
+    stat_write_request: bool,
     lyc_selected: bool,
     oamscan_selected: bool,
     vblank_selected: bool,
@@ -167,6 +303,16 @@ pub struct PPU
     scx: u8,
     wy: u8,
     wx: u8,
+    //WY only has to match LY on one line of the frame, after that the window can show on any line
+    window_y_triggered: bool,
+    //The window's own line counter, only moves on lines the window was actually drawn
+    window_line: u8,
+    //WX=166 carries the window on through the whole of the following line
+    window_fills_line: bool,
+    //Finished picture in 0RGB so a frontend can hand it straight to the window
+    frame_buffer: [u32; SCREEN_WIDTH * SCREEN_HEIGHT],
+    //The first frame after the LCD is switched on never makes it to the screen
+    skip_frame: bool,
 }
 #[derive(Clone, Copy)]
 pub enum PPUModes
@@ -184,22 +330,32 @@ impl PPU
         {
             vram: [0; VRAM_SIZE],
             oam: [Object::new(); NUMBER_OF_OBJECTS],
+            line_objects: [0; OBJECTS_PER_LINE],
+            line_object_count: 0,
             tiles: [empty_tile(); TILE_COUNT],
             cycles: 0,
-            mode: PPUModes::OAMScan,
+            mode: PPUModes::HBlank,
+            renderer: Renderer::Scanline,
+            line_renderer: Renderer::Scanline,
+            fifo: PixelFifo::new(),
+            pixel_transfer_cycles: PIXEL_TRANSFER_CYCLES,
+            bgp: Palette::new(),
             obp0: Palette::new(),
             obp1: Palette::new(),
-            lcd_enabled: true,
+            //LCDC is 0 at power on, it's the boot ROM that switches the LCD on
+            lcd_enabled: false,
             window_tilemap: TileMapArea::X9800,
             window_enabled: false,
-            bg_window_tiles: BGWindowTiles::X8000,
+            bg_window_tiles: BGWindowTiles::X8800,
             bg_tilemap: TileMapArea::X9800,
             object_size: ObjectSize::O8x8,
             object_enabled: false,
-            bg_window_enabled: true,
+            bg_window_enabled: false,
             ly: 0,
             lyc: 0,
             ly_is_lyc: false,
+            stat_interrupt_line: false,
+            stat_write_request: false,
             lyc_selected: false,
             oamscan_selected: false,
             vblank_selected: false,
@@ -208,19 +364,151 @@ impl PPU
             scx: 0,
             wy: 0,
             wx: 0,
+            window_y_triggered: false,
+            window_line: 0,
+            window_fills_line: false,
+            frame_buffer: [BLANK; SCREEN_WIDTH * SCREEN_HEIGHT],
+            skip_frame: false,
         }
     }
+    pub fn renderer(&self) -> Renderer
+    {
+        self.renderer
+    }
+    //Takes effect from the next line
+    pub fn set_renderer(&mut self, renderer: Renderer)
+    {
+        self.renderer = renderer;
+    }
+    pub fn frame_buffer(&self) -> &[u32]
+    {
+        &self.frame_buffer
+    }
+    //0xFF40-0xFF4B
+    pub fn read_register(&self, address: usize) -> u8
+    {
+        match address
+        {
+            LCDC =>
+            {
+                (self.lcd_enabled as u8) << 7
+                | (matches!(self.window_tilemap, TileMapArea::X9C00) as u8) << 6
+                | (self.window_enabled as u8) << 5
+                | (matches!(self.bg_window_tiles, BGWindowTiles::X8000) as u8) << 4
+                | (matches!(self.bg_tilemap, TileMapArea::X9C00) as u8) << 3
+                | (matches!(self.object_size, ObjectSize::O8x16) as u8) << 2
+                | (self.object_enabled as u8) << 1
+                | self.bg_window_enabled as u8
+            },
+            //Bit 7 is unused and reads as 1
+            STAT =>
+            {
+                0x80
+                | (self.lyc_selected as u8) << 6
+                | (self.oamscan_selected as u8) << 5
+                | (self.vblank_selected as u8) << 4
+                | (self.hblank_selected as u8) << 3
+                | (self.ly_is_lyc as u8) << 2
+                | match self.mode
+                {
+                    PPUModes::HBlank => 0,
+                    PPUModes::VBlank => 1,
+                    PPUModes::OAMScan => 2,
+                    PPUModes::PixelTransfer => 3,
+                }
+            },
+            SCY => self.scy,
+            SCX => self.scx,
+            LY => self.ly,
+            LYC => self.lyc,
+            BGP => (&self.bgp).into(),
+            OBP0 => (&self.obp0).into(),
+            OBP1 => (&self.obp1).into(),
+            WY => self.wy,
+            WX => self.wx,
+            _ => 0xFF,
+        }
+    }
+    pub fn write_register(&mut self, address: usize, value: u8)
+    {
+        match address
+        {
+            LCDC =>
+            {
+                let enabled = (value & 0x80) != 0;
+                if self.lcd_enabled && !enabled
+                {
+                    self.switch_off();
+                }
+                else if !self.lcd_enabled && enabled
+                {
+                    self.switch_on();
+                }
+                self.lcd_enabled = enabled;
+                self.window_tilemap = if (value & 0x40) != 0 {TileMapArea::X9C00} else {TileMapArea::X9800};
+                self.window_enabled = (value & 0x20) != 0;
+                self.bg_window_tiles = if (value & 0x10) != 0 {BGWindowTiles::X8000} else {BGWindowTiles::X8800};
+                self.bg_tilemap = if (value & 0x08) != 0 {TileMapArea::X9C00} else {TileMapArea::X9800};
+                self.object_size = if (value & 0x04) != 0 {ObjectSize::O8x16} else {ObjectSize::O8x8};
+                self.object_enabled = (value & 0x02) != 0;
+                self.bg_window_enabled = (value & 0x01) != 0;
+            },
+            //The mode and coincidence bits are read only
+            STAT =>
+            {
+                //On DMG the write sets every select bit for a cycle first, so the interrupt fires if anything
+                //but mode 3 is going on
+                if self.lcd_enabled && !self.stat_interrupt_line && self.stat_line(true, true, true, true)
+                {
+                    self.stat_write_request = true;
+                }
+                self.lyc_selected = (value & 0x40) != 0;
+                self.oamscan_selected = (value & 0x20) != 0;
+                self.vblank_selected = (value & 0x10) != 0;
+                self.hblank_selected = (value & 0x08) != 0;
+            },
+            SCY => self.scy = value,
+            SCX => self.scx = value,
+            //LY is read only
+            LYC =>
+            {
+                self.lyc = value;
+                self.ly_is_lyc = self.ly == self.lyc;
+            },
+            BGP => self.bgp = value.into(),
+            OBP0 => self.obp0 = value.into(),
+            OBP1 => self.obp1 = value.into(),
+            WY => self.wy = value,
+            WX => self.wx = value,
+            _ => {},
+        }
+    }
+    //LY goes back to 0 and everything stops in mode 0 with a blank screen
+    fn switch_off(&mut self)
+    {
+        self.ly = 0;
+        self.cycles = 0;
+        self.mode = PPUModes::HBlank;
+        self.stat_interrupt_line = false;
+        self.start_frame();
+        self.frame_buffer.fill(BLANK);
+    }
+    //Starts from the top of a frame that's drawn but not shown, the screen stays blank until the next one
+    fn switch_on(&mut self)
+    {
+        self.cycles = 0;
+        self.mode = PPUModes::OAMScan;
+        self.skip_frame = true;
+        self.ly_is_lyc = self.ly == self.lyc;
+    }
     pub fn write_to_vram(&mut self, address: usize, value: u8)
     {
         self.vram[address] = value;
-        if address >= 0x1800
+        //The tile maps are just indices, only tile data needs decoding
+        if address >= TILE_DATA_SIZE
         {
             return;
         }
-        else
-        {
-            self.write_oam(address, value);   
-        }
         //We need to recreate the tile row if we change one of its bytes. Remember, tiles' rows start at even addresses.
         let tile_start_add = address & 0xFFFE;
         let byte1 = self.vram[tile_start_add];
@@ -239,7 +527,7 @@ impl PPU
                 (false, true) => TilePixelValue::One,
                 (false, false) => TilePixelValue::Zero
             };
-            self.tiles[i][tile_row][tile] = pixel_colour;
+            self.tiles[tile][tile_row][i] = pixel_colour;
         }
     }
     pub fn read_from_vram(&mut self, address: usize) -> u8
@@ -254,24 +542,19 @@ impl PPU
         {
             0   => self.oam[index].y = value,
             1   => self.oam[index].x = value,
-            2   => {
-                        match self.object_size
-                        {
-                            ObjectSize::O8x8 => {self.oam[index].tile_index = value;},
-                            ObjectSize::O8x16 => {self.oam[index].tile_index = value & 0xFE;},
-                        }
-                    }
+            //Stored as written, 8x16 objects ignore bit 0 when they're drawn
+            2   => self.oam[index].tile_index = value,
             3   =>  {
                         self.oam[index].priority = (value & 0x80) != 0;
                         self.oam[index].y_flip = (value & 0x40) != 0;
                         self.oam[index].x_flip = (value & 0x20) != 0;
                         if (value & 0x10) != 0
                         {
-                            self.oam[index].pallette = ObjectPalette::Zero;
+                            self.oam[index].pallette = ObjectPalette::One;
                         }
                         else 
                         {
-                            self.oam[index].pallette = ObjectPalette::One;    
+                            self.oam[index].pallette = ObjectPalette::Zero;    
                         }
                     }
             _   => panic!("WRITING TO UNKNOWN OBJECT 0X{:x}", address),
@@ -285,15 +568,9 @@ impl PPU
         {
             0   => self.oam[index].y,
             1   => self.oam[index].x,
-            2   =>  {
-                        match self.object_size
-                        {
-                            ObjectSize::O8x8 => {self.oam[index].tile_index},
-                            ObjectSize::O8x16 => {self.oam[index].tile_index & 0xFE},
-                        }
-                    }
+            2   => self.oam[index].tile_index,
             3   =>  {
-                        0x0 | ((self.oam[index].priority as u8) << 7)
+                        ((self.oam[index].priority as u8) << 7)
                         | ((self.oam[index].y_flip as u8) << 6)
                         | ((self.oam[index].x_flip as u8) << 5)
                         | match self.oam[index].pallette {ObjectPalette::One => {1 << 4}, ObjectPalette::Zero => {0 << 4}}
@@ -301,92 +578,1036 @@ impl PPU
             _   => panic!("READING FROM UNKNOWN LINE 0X{:x}", address),
         }
     }
-    pub fn step(&mut self, cycles: u8) -> Interrupts
+    //Requests VBlank and STAT interrupts straight into IF
+    pub fn step(&mut self, cycles: u8, interrupts: &mut InterruptFlags)
     {
-        let mut request = Interrupts::None;
+        //The PPU's clock stops while the LCD is off
+        if !self.lcd_enabled
+        {
+            return;
+        }
         let mode = self.mode;
         self.cycles += cycles as u16;
         match mode
         {
             PPUModes::OAMScan => 
             {
-                if self.cycles >= 80
+                if self.cycles >= OAM_SCAN_CYCLES
                 {
-                    self.cycles = self.cycles % 80;
+                    self.cycles -= OAM_SCAN_CYCLES;
+                    self.scan_oam();
+                    self.line_renderer = self.renderer;
+                    if self.line_renderer == Renderer::PixelFifo
+                    {
+                        self.start_fifo();
+                    }
                     self.mode = PPUModes::PixelTransfer;
                 }
             },
             PPUModes::PixelTransfer => 
             {
-                if self.cycles >= 172
+                let finished = match self.line_renderer
                 {
-                    self.cycles = self.cycles % 172;
-                    if self.hblank_selected
+                    Renderer::Scanline if self.cycles >= PIXEL_TRANSFER_CYCLES =>
                     {
-                        request.add(Interrupts::LCD);
-                    }
+                        self.render_scanline();
+                        self.pixel_transfer_cycles = PIXEL_TRANSFER_CYCLES;
+                        true
+                    },
+                    Renderer::Scanline => false,
+                    Renderer::PixelFifo => self.run_fifo(),
+                };
+                if finished
+                {
+                    self.cycles -= self.pixel_transfer_cycles;
                     self.mode = PPUModes::HBlank;
-                    //Render a scan line here
                 }
             },
             PPUModes::HBlank => 
             {
-                if self.cycles >= 284
+                let hblank_cycles = (LINE_CYCLES - OAM_SCAN_CYCLES).saturating_sub(self.pixel_transfer_cycles);
+                if self.cycles >= hblank_cycles
                 {
-                    self.cycles = self.cycles % 284;
+                    self.cycles -= hblank_cycles;
                     self.ly += 1;
-                    if self.ly >= 144
+                    if self.ly as usize == SCREEN_HEIGHT
                     {
-                        if self.vblank_selected
-                        {
-                            self.mode = PPUModes::VBlank;
-                            request.add(Interrupts::VBlank);
-                            if self.vblank_selected
-                            {
-                                request.add(Interrupts::LCD);
-                            }
-                        }
-                        else
-                        {
-                            self.mode = PPUModes::OAMScan;
-                            if self.oamscan_selected
-                            {
-                                request.add(Interrupts::LCD);
-                            }
-                        }
-                        request = self.lyc_check(request);
+                        self.mode = PPUModes::VBlank;
+                        interrupts.request(Interrupt::VBlank);
+                    }
+                    else
+                    {
+                        //On to the next visible line
+                        self.mode = PPUModes::OAMScan;
                     }
+                    self.ly_is_lyc = self.ly == self.lyc;
                 }
             },
             PPUModes::VBlank => 
             {
-                if self.cycles >= 456
+                if self.cycles >= LINE_CYCLES
                 {
-                    self.cycles = self.cycles % 456;
+                    self.cycles -= LINE_CYCLES;
                     self.ly += 1;
                     if self.ly == 154
                     {
                         self.mode = PPUModes::OAMScan;
                         self.ly = 0;
-                        if self.oamscan_selected
-                        {
-                            request.add(Interrupts::LCD);
-                        }
+                        self.start_frame();
                     }
-                    request = self.lyc_check(request);
+                    self.ly_is_lyc = self.ly == self.lyc;
                 }
             },
         }
-        request
+        if self.update_stat_line()
+        {
+            interrupts.request(Interrupt::LCDStat);
+        }
+    }
+    //All the STAT sources are ORed onto one interrupt line and only a rising edge requests the interrupt, so a
+    //source that comes on while another is already holding the line up gets lost ("STAT blocking")
+    fn stat_line(&self, lyc_selected: bool, oamscan_selected: bool, vblank_selected: bool, hblank_selected: bool) -> bool
+    {
+        (lyc_selected && self.ly_is_lyc)
+        || match self.mode
+        {
+            PPUModes::OAMScan => oamscan_selected,
+            PPUModes::VBlank => vblank_selected,
+            PPUModes::HBlank => hblank_selected,
+            PPUModes::PixelTransfer => false,
+        }
+    }
+    //Returns true on a rising edge
+    fn update_stat_line(&mut self) -> bool
+    {
+        let line = self.lcd_enabled && self.stat_line(self.lyc_selected, self.oamscan_selected, self.vblank_selected, self.hblank_selected);
+        let rising = line && !self.stat_interrupt_line;
+        self.stat_interrupt_line = line;
+        //Taken either way, so a STAT write landing on a rising edge doesn't request a second interrupt later
+        let write_request = std::mem::take(&mut self.stat_write_request);
+        rising || write_request
+    }
+    //Pixel value at a point in a 256x256 tile map, with the tile data area LCDC picks
+    fn tile_map_pixel(&self, tile_map: &TileMapArea, x: u8, y: u8) -> TilePixelValue
+    {
+        self.tiles[self.tile_map_tile(tile_map, x, y)][y as usize % 8][x as usize % 8]
+    }
+    fn tile_map_tile(&self, tile_map: &TileMapArea, x: u8, y: u8) -> usize
+    {
+        let base = match tile_map
+        {
+            TileMapArea::X9800 => TILE_MAP_9800,
+            TileMapArea::X9C00 => TILE_MAP_9C00,
+        };
+        let index = self.vram[base + (y as usize / 8) * 32 + x as usize / 8];
+        //0x8800 addressing counts signed from tile 256 (0x9000)
+        match self.bg_window_tiles
+        {
+            BGWindowTiles::X8000 => index as usize,
+            BGWindowTiles::X8800 => (256 + index as i8 as i16) as usize,
+        }
+    }
+    //Draws line LY into the frame buffer, run as pixel transfer finishes
+    fn render_scanline(&mut self)
+    {
+        let y = self.ly as usize;
+        if y >= SCREEN_HEIGHT
+        {
+            return;
+        }
+        let window_x = self.window_start();
+        let window_visible = window_x.is_some();
+        let window_x = window_x.unwrap_or(0);
+        //Kept for the objects, which can hide behind background colours 1-3
+        let mut background = [TilePixelValue::Zero; SCREEN_WIDTH];
+        for (x, pixel) in background.iter_mut().enumerate()
+        {
+            //LCDC bit 0 off blanks background and window to colour 0
+            *pixel = if !self.bg_window_enabled
+            {
+                TilePixelValue::Zero
+            }
+            else if window_visible && x + 7 >= window_x
+            {
+                self.tile_map_pixel(&self.window_tilemap, (x + 7 - window_x) as u8, self.window_line)
+            }
+            else
+            {
+                self.tile_map_pixel(&self.bg_tilemap, self.scx.wrapping_add(x as u8), self.scy.wrapping_add(self.ly))
+            };
+            let colour = self.bgp.rgb(*pixel);
+            self.set_pixel(x, y, colour);
+        }
+        if window_visible
+        {
+            self.window_line += 1;
+        }
+        if self.object_enabled
+        {
+            self.render_objects(y, &background);
+        }
+    }
+    fn set_pixel(&mut self, x: usize, y: usize, colour: u32)
+    {
+        if y < SCREEN_HEIGHT && !self.skip_frame
+        {
+            self.frame_buffer[y * SCREEN_WIDTH + x] = colour;
+        }
+    }
+    //LCDC bits 0 and 5 are looked at every line, so the window can be switched off for a few lines and pick up where it
+    //left off. Its line counter only moves on lines it was drawn.
+    //WX is the window's left edge plus 7: 0-6 start it partly off the left side, past 166 it never starts.
+    fn window_start(&mut self) -> Option<usize>
+    {
+        let visible = self.bg_window_enabled && self.window_enabled && self.window_y_triggered && self.wx <= 166;
+        let window_x = if self.window_fills_line {7} else {self.wx as usize};
+        self.window_fills_line = visible && self.wx == 166;
+        visible.then_some(window_x)
+    }
+    fn start_frame(&mut self)
+    {
+        self.skip_frame = false;
+        self.window_y_triggered = false;
+        self.window_line = 0;
+        self.window_fills_line = false;
+    }
+    fn object_height(&self) -> usize
+    {
+        match self.object_size
+        {
+            ObjectSize::O8x8 => 8,
+            ObjectSize::O8x16 => 16,
+        }
+    }
+    //Picks the first 10 objects in OAM that cover this line. X doesn't matter here, objects off the sides
+    //still use up a slot. On DMG the one with the lower X is drawn on top, the lower OAM index on a tie.
+    fn scan_oam(&mut self)
+    {
+        if self.ly == self.wy
+        {
+            self.window_y_triggered = true;
+        }
+        let height = self.object_height();
+        //Y is stored offset by 16 so objects can sit partly off the top
+        let line = self.ly as usize + 16;
+        self.line_object_count = 0;
+        for (index, object) in self.oam.iter().enumerate()
+        {
+            if self.line_object_count == OBJECTS_PER_LINE
+            {
+                break;
+            }
+            if (object.y as usize..object.y as usize + height).contains(&line)
+            {
+                self.line_objects[self.line_object_count] = index;
+                self.line_object_count += 1;
+            }
+        }
+        let oam = &self.oam;
+        //Stable, so OAM order decides between equal X
+        self.line_objects[..self.line_object_count].sort_by_key(|&index| oam[index].x);
+    }
+    fn render_objects(&mut self, y: usize, background: &[TilePixelValue; SCREEN_WIDTH])
+    {
+        //Once the highest priority object has an opaque pixel somewhere the ones below can't show there,
+        //even if that object is itself hidden behind the background
+        let mut covered = [false; SCREEN_WIDTH];
+        let line_objects = self.line_objects;
+        for &index in &line_objects[..self.line_object_count]
+        {
+            let object = self.oam[index];
+            let Some((tile, row)) = self.object_row(&object, y) else
+            {
+                continue;
+            };
+            let palette = match object.pallette
+            {
+                ObjectPalette::Zero => self.obp0,
+                ObjectPalette::One => self.obp1,
+            };
+            for column in 0..8
+            {
+                //X is stored offset by 8
+                let x = object.x as usize + column;
+                if !(8..SCREEN_WIDTH + 8).contains(&x) || covered[x - 8]
+                {
+                    continue;
+                }
+                let x = x - 8;
+                let tile_column = if object.x_flip {7 - column} else {column};
+                let value = self.tiles[tile][row][tile_column];
+                //Colour 0 is transparent on objects
+                if value == TilePixelValue::Zero
+                {
+                    continue;
+                }
+                covered[x] = true;
+                //With the priority bit set the object only shows through background colour 0
+                if object.priority && background[x] != TilePixelValue::Zero
+                {
+                    continue;
+                }
+                let colour = palette.rgb(value);
+                self.set_pixel(x, y, colour);
+            }
+        }
+    }
+    //Row of an object's tile that lands on this line, None if it doesn't (OAM and the object size can change
+    //between the scan and drawing it)
+    fn object_row(&self, object: &Object, y: usize) -> Option<(usize, usize)>
+    {
+        let height = self.object_height();
+        let mut row = (y + 16).checked_sub(object.y as usize).filter(|&row| row < height)?;
+        if object.y_flip
+        {
+            row = height - 1 - row;
+        }
+        //8x16 objects are an even tile on top of the odd one after it
+        let tile = match self.object_size
+        {
+            ObjectSize::O8x8 => object.tile_index as usize,
+            ObjectSize::O8x16 => (object.tile_index & 0xFE) as usize + row / 8,
+        };
+        Some((tile, row % 8))
+    }
+    fn start_fifo(&mut self)
+    {
+        let window_x = self.window_start();
+        let fifo = &mut self.fifo;
+        fifo.background.clear();
+        fifo.objects.clear();
+        fifo.step = FetcherStep::Tile;
+        fifo.step_cycles = 0;
+        fifo.startup = FIFO_STARTUP_CYCLES;
+        fifo.tile_x = 0;
+        fifo.discard = self.scx % 8;
+        fifo.x = 0;
+        fifo.cycles = 0;
+        fifo.window_x = window_x;
+        fifo.window = false;
+        fifo.next_object = 0;
+        fifo.object_fetch = None;
+        fifo.object_tile = None;
     }
-    pub fn lyc_check(&mut self, mut request: Interrupts) -> Interrupts
+    //Catches the FIFO up with the cycles pixel transfer has had, true once the line is finished
+    fn run_fifo(&mut self) -> bool
     {
-        let check = self.ly == self.lyc;
-        if check & self.lyc_selected
+        while self.fifo.cycles < self.cycles
         {
-            request.add(Interrupts::LCD);
+            self.fifo.cycles += 1;
+            if self.fifo_dot()
+            {
+                self.pixel_transfer_cycles = self.fifo.cycles;
+                if self.fifo.window
+                {
+                    self.window_line += 1;
+                }
+                return true;
+            }
         }
-        self.ly_is_lyc = check;
-        request
+        false
     }
-}
\ No newline at end of file
+    //One dot of pixel transfer, true when the last pixel of the line has gone out
+    fn fifo_dot(&mut self) -> bool
+    {
+        if self.fifo.startup > 0
+        {
+            self.fifo.startup -= 1;
+            return false;
+        }
+        //Reaching WX throws away what's queued and starts fetching the window from its first tile
+        if let Some(window_x) = self.fifo.window_x
+        {
+            if !self.fifo.window && self.window_enabled && self.fifo.x + 7 >= window_x
+            {
+                self.fifo.window = true;
+                self.fifo.background.clear();
+                self.fifo.step = FetcherStep::Tile;
+                self.fifo.step_cycles = 0;
+                self.fifo.tile_x = 0;
+                self.fifo.object_tile = None;
+                //A window left of the screen edge is cut off instead of scrolled
+                self.fifo.discard = if window_x < 7 {7 - window_x as u8} else {0};
+            }
+        }
+        //Objects are fetched in X order as the output reaches them
+        if self.fifo.object_fetch.is_none() && self.object_enabled && self.fifo.next_object < self.line_object_count
+        {
+            let x = self.oam[self.line_objects[self.fifo.next_object]].x as usize;
+            if x <= self.fifo.x + 8
+            {
+                self.fifo.object_fetch = Some(self.object_fetch_cycles(x));
+            }
+        }
+        if let Some(remaining) = self.fifo.object_fetch
+        {
+            if remaining > 1
+            {
+                self.fifo.object_fetch = Some(remaining - 1);
+            }
+            else
+            {
+                self.fifo.object_fetch = None;
+                self.fetch_object(self.line_objects[self.fifo.next_object]);
+                self.fifo.next_object += 1;
+            }
+            return false;
+        }
+        self.fetcher_dot();
+        let Some(mut background) = self.fifo.background.pop_front() else
+        {
+            return false;
+        };
+        if self.fifo.discard > 0
+        {
+            self.fifo.discard -= 1;
+            return false;
+        }
+        if !self.bg_window_enabled
+        {
+            background = TilePixelValue::Zero;
+        }
+        let colour = match self.fifo.objects.pop_front()
+        {
+            Some(object) if object.value != TilePixelValue::Zero && !(object.priority && background != TilePixelValue::Zero) =>
+            {
+                match object.palette
+                {
+                    ObjectPalette::Zero => self.obp0.rgb(object.value),
+                    ObjectPalette::One => self.obp1.rgb(object.value),
+                }
+            },
+            _ => self.bgp.rgb(background),
+        };
+        self.set_pixel(self.fifo.x, self.ly as usize, colour);
+        self.fifo.x += 1;
+        self.fifo.x == SCREEN_WIDTH
+    }
+    //The first object in a background tile waits for the fetcher to finish that tile, longer the further left in it
+    //the object starts: 0-5 dots on top of the fetch itself
+    fn object_fetch_cycles(&mut self, x: usize) -> u8
+    {
+        //Where the object's left edge falls in the layer being fetched. X's offset of 8 is a whole tile.
+        let position = match self.fifo.window_x
+        {
+            Some(window_x) if self.fifo.window => x + 7 - window_x,
+            _ => x + self.scx as usize,
+        };
+        let wait = if self.fifo.object_tile == Some(position / 8) {0} else {(8 - position % 8).saturating_sub(2).min(5)};
+        self.fifo.object_tile = Some(position / 8);
+        OBJECT_FETCH_CYCLES + wait as u8
+    }
+    //Background/window fetcher: tile number, two bytes of tile data, then 8 pixels into the FIFO once it's empty
+    fn fetcher_dot(&mut self)
+    {
+        if self.fifo.step == FetcherStep::Push
+        {
+            if self.fifo.background.is_empty()
+            {
+                let row = self.tiles[self.fifo.tile][self.fifo.tile_row];
+                self.fifo.background.extend(row);
+                self.fifo.tile_x = self.fifo.tile_x.wrapping_add(1);
+                self.fifo.step = FetcherStep::Tile;
+            }
+            return;
+        }
+        self.fifo.step_cycles += 1;
+        if self.fifo.step_cycles < 2
+        {
+            return;
+        }
+        self.fifo.step_cycles = 0;
+        self.fifo.step = match self.fifo.step
+        {
+            FetcherStep::Tile =>
+            {
+                //Scroll registers are read fresh for every tile
+                let (tile_map, x, y) = if self.fifo.window
+                {
+                    (&self.window_tilemap, self.fifo.tile_x.wrapping_mul(8), self.window_line)
+                }
+                else
+                {
+                    (&self.bg_tilemap, (self.scx / 8).wrapping_add(self.fifo.tile_x).wrapping_mul(8), self.scy.wrapping_add(self.ly))
+                };
+                self.fifo.tile = self.tile_map_tile(tile_map, x, y);
+                self.fifo.tile_row = y as usize % 8;
+                FetcherStep::DataLow
+            },
+            FetcherStep::DataLow => FetcherStep::DataHigh,
+            FetcherStep::DataHigh | FetcherStep::Push => FetcherStep::Push,
+        };
+    }
+    //Mixes an object's row into the object FIFO. Pixels already there from an object with higher priority stay
+    //unless they're transparent.
+    fn fetch_object(&mut self, index: usize)
+    {
+        let object = self.oam[index];
+        let Some((tile, row)) = self.object_row(&object, self.ly as usize) else
+        {
+            return;
+        };
+        //Columns already past the left edge, for objects partly off screen
+        let skip = (self.fifo.x + 8).saturating_sub(object.x as usize).min(8);
+        while self.fifo.objects.len() < 8 - skip
+        {
+            self.fifo.objects.push_back(ObjectPixel { value: TilePixelValue::Zero, palette: ObjectPalette::Zero, priority: false });
+        }
+        for column in skip..8
+        {
+            let tile_column = if object.x_flip {7 - column} else {column};
+            let value = self.tiles[tile][row][tile_column];
+            let slot = &mut self.fifo.objects[column - skip];
+            if slot.value == TilePixelValue::Zero
+            {
+                *slot = ObjectPixel { value, palette: object.pallette, priority: object.priority };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    //Identity palettes and tiles 1-3 filled with their own colour, so a pixel's shade says which tile it came from
+    fn ppu(lcdc: u8) -> PPU
+    {
+        let mut ppu = PPU::new();
+        for register in [BGP, OBP0, OBP1]
+        {
+            ppu.write_register(register, 0xE4);
+        }
+        for tile in 1..4
+        {
+            fill_tile(&mut ppu, tile, tile as u8);
+        }
+        ppu.write_register(LCDC, lcdc);
+        ppu
+    }
+    fn fill_tile(ppu: &mut PPU, tile: usize, colour: u8)
+    {
+        for row in 0..8
+        {
+            ppu.write_to_vram(tile * 16 + row * 2, if (colour & 1) != 0 {0xFF} else {0x00});
+            ppu.write_to_vram(tile * 16 + row * 2 + 1, if (colour & 2) != 0 {0xFF} else {0x00});
+        }
+    }
+    fn run_lines(ppu: &mut PPU, lines: usize)
+    {
+        let mut interrupts = InterruptFlags::new();
+        for _ in 0..(lines * LINE_CYCLES as usize / 4)
+        {
+            ppu.step(4, &mut interrupts);
+        }
+    }
+    //The first frame after the LCD comes on isn't shown
+    fn draw(ppu: &mut PPU)
+    {
+        run_lines(ppu, 2 * 154);
+    }
+    fn shade(ppu: &PPU, x: usize, y: usize) -> u8
+    {
+        match ppu.frame_buffer()[y * SCREEN_WIDTH + x]
+        {
+            0xFFFFFF => 0,
+            0xC0C0C0 => 1,
+            0x606060 => 2,
+            0x000000 => 3,
+            colour => panic!("not a DMG shade {:06x}", colour),
+        }
+    }
+
+    #[test]
+    fn background_scrolls_and_wraps()
+    {
+        let mut ppu = ppu(0x91);
+        ppu.write_to_vram(TILE_MAP_9800, 1);
+        ppu.write_to_vram(TILE_MAP_9800 + 1, 2);
+        ppu.write_to_vram(TILE_MAP_9800 + 32, 3);
+        ppu.write_register(SCX, 4);
+        ppu.write_register(SCY, 4);
+        draw(&mut ppu);
+        assert_eq!(shade(&ppu, 0, 0), 1);
+        assert_eq!(shade(&ppu, 3, 3), 1);
+        assert_eq!(shade(&ppu, 4, 0), 2);
+        assert_eq!(shade(&ppu, 0, 4), 3);
+        assert_eq!(shade(&ppu, 12, 0), 0);
+        //Scrolling past the right edge of the map comes back round to its left
+        ppu.write_register(SCX, 252);
+        ppu.write_register(SCY, 0);
+        draw(&mut ppu);
+        assert_eq!(shade(&ppu, 3, 0), 0);
+        assert_eq!(shade(&ppu, 4, 0), 1);
+        assert_eq!(shade(&ppu, 12, 0), 2);
+        assert_eq!(shade(&ppu, 4, 8), 3);
+    }
+
+    #[test]
+    fn signed_tile_numbers_at_0x8800()
+    {
+        let mut ppu = ppu(0x81);
+        fill_tile(&mut ppu, 256, 2);
+        fill_tile(&mut ppu, 255, 3);
+        ppu.write_to_vram(TILE_MAP_9800 + 1, 0xFF);
+        ppu.write_to_vram(TILE_MAP_9800 + 2, 0x80);
+        draw(&mut ppu);
+        //0 is tile 256, 0xFF the one before it and 0x80 is the same tile 128 as 0x8000 addressing
+        assert_eq!(shade(&ppu, 0, 0), 2);
+        assert_eq!(shade(&ppu, 8, 0), 3);
+        assert_eq!(shade(&ppu, 16, 0), 0);
+        fill_tile(&mut ppu, 128, 1);
+        draw(&mut ppu);
+        assert_eq!(shade(&ppu, 16, 0), 1);
+    }
+
+    #[test]
+    fn palettes_and_lcdc_bit_0()
+    {
+        let mut ppu = ppu(0x91);
+        ppu.write_to_vram(TILE_MAP_9800, 1);
+        ppu.write_register(BGP, 0x1B);
+        draw(&mut ppu);
+        assert_eq!(shade(&ppu, 0, 0), 2);
+        assert_eq!(shade(&ppu, 8, 0), 3);
+        //Background off draws colour 0 through the palette
+        ppu.write_register(LCDC, 0x90);
+        draw(&mut ppu);
+        assert_eq!(shade(&ppu, 0, 0), 3);
+    }
+
+    fn object(ppu: &mut PPU, index: usize, y: u8, x: u8, tile: u8, attributes: u8)
+    {
+        for (byte, value) in [y, x, tile, attributes].into_iter().enumerate()
+        {
+            ppu.write_oam(index * 4 + byte, value);
+        }
+    }
+
+    #[test]
+    fn ten_objects_per_line()
+    {
+        let mut ppu = ppu(0x93);
+        //Off the left edge but still on the line, so it takes a slot
+        object(&mut ppu, 0, 16, 0, 3, 0x00);
+        for index in 1..10
+        {
+            object(&mut ppu, index, 16, 8 + 8 * index as u8, 3, 0x00);
+        }
+        //Lines 4-11, the 11th object on lines 4-7
+        object(&mut ppu, 10, 20, 88, 3, 0x00);
+        draw(&mut ppu);
+        for index in 1..10
+        {
+            assert_eq!(shade(&ppu, 8 * index, 4), 3);
+        }
+        assert_eq!(shade(&ppu, 80, 4), 0);
+        assert_eq!(shade(&ppu, 80, 8), 3);
+    }
+
+    #[test]
+    fn lower_x_then_lower_oam_index_on_top()
+    {
+        let mut ppu = ppu(0x93);
+        //Left half colour 3, right half transparent
+        for row in 0..8
+        {
+            ppu.write_to_vram(4 * 16 + row * 2, 0xF0);
+            ppu.write_to_vram(4 * 16 + row * 2 + 1, 0xF0);
+        }
+        object(&mut ppu, 0, 16, 12, 1, 0x00);
+        object(&mut ppu, 1, 16, 8, 2, 0x00);
+        object(&mut ppu, 2, 16, 40, 1, 0x00);
+        object(&mut ppu, 3, 16, 40, 2, 0x00);
+        object(&mut ppu, 4, 16, 72, 4, 0x00);
+        object(&mut ppu, 5, 16, 76, 1, 0x00);
+        draw(&mut ppu);
+        assert_eq!(shade(&ppu, 4, 0), 2);
+        assert_eq!(shade(&ppu, 8, 0), 1);
+        assert_eq!(shade(&ppu, 32, 0), 1);
+        //Transparent pixels let the object underneath through
+        assert_eq!(shade(&ppu, 64, 0), 3);
+        assert_eq!(shade(&ppu, 68, 0), 1);
+    }
+
+    #[test]
+    fn tall_objects_ignore_tile_bit_0()
+    {
+        let mut ppu = ppu(0x97);
+        object(&mut ppu, 0, 16, 8, 3, 0x00);
+        object(&mut ppu, 1, 16, 16, 3, 0x40);
+        draw(&mut ppu);
+        assert_eq!(shade(&ppu, 0, 0), 2);
+        assert_eq!(shade(&ppu, 0, 15), 3);
+        assert_eq!(shade(&ppu, 0, 16), 0);
+        //Y flip turns the whole 8x16 object over, not each tile
+        assert_eq!(shade(&ppu, 8, 0), 3);
+        assert_eq!(shade(&ppu, 8, 15), 2);
+    }
+
+    #[test]
+    fn flips()
+    {
+        let mut ppu = ppu(0x93);
+        //Only the top left pixel set
+        ppu.write_to_vram(4 * 16, 0x80);
+        ppu.write_to_vram(4 * 16 + 1, 0x80);
+        for (index, attributes) in [0x00, 0x20, 0x40, 0x60].into_iter().enumerate()
+        {
+            object(&mut ppu, index, 16, 8 + 16 * index as u8, 4, attributes);
+        }
+        draw(&mut ppu);
+        assert_eq!(shade(&ppu, 0, 0), 3);
+        assert_eq!(shade(&ppu, 7, 0), 0);
+        assert_eq!(shade(&ppu, 23, 0), 3);
+        assert_eq!(shade(&ppu, 16, 0), 0);
+        assert_eq!(shade(&ppu, 32, 7), 3);
+        assert_eq!(shade(&ppu, 32, 0), 0);
+        assert_eq!(shade(&ppu, 55, 7), 3);
+    }
+
+    #[test]
+    fn palettes_and_background_priority()
+    {
+        let mut ppu = ppu(0x93);
+        ppu.write_to_vram(TILE_MAP_9800, 1);
+        ppu.write_register(OBP1, 0x1B);
+        //Behind background colours 1-3, over colour 0
+        object(&mut ppu, 0, 16, 12, 3, 0x80);
+        //Lower priority than object 0, so it can't show where that one is opaque even though it's hidden
+        object(&mut ppu, 1, 16, 13, 2, 0x00);
+        object(&mut ppu, 2, 16, 40, 1, 0x10);
+        draw(&mut ppu);
+        assert_eq!(shade(&ppu, 4, 0), 1);
+        assert_eq!(shade(&ppu, 5, 0), 1);
+        assert_eq!(shade(&ppu, 8, 0), 3);
+        assert_eq!(shade(&ppu, 12, 0), 2);
+        assert_eq!(shade(&ppu, 32, 0), 2);
+        //Objects off
+        ppu.write_register(LCDC, 0x91);
+        draw(&mut ppu);
+        assert_eq!(shade(&ppu, 8, 0), 0);
+    }
+
+    #[test]
+    fn window_keeps_its_own_line_counter()
+    {
+        for renderer in [Renderer::Scanline, Renderer::PixelFifo]
+        {
+            //Window map at 0x9C00, a different tile every 8 window lines
+            let mut ppu = ppu(0xF1);
+            ppu.set_renderer(renderer);
+            for row in 0..18
+            {
+                ppu.write_to_vram(TILE_MAP_9C00 + row * 32, (row % 3) as u8 + 1);
+            }
+            ppu.write_register(WY, 20);
+            ppu.write_register(WX, 7);
+            run_lines(&mut ppu, 154 + 28);
+            //Hidden on lines 28-35 by LCDC bit 5 and 44-51 by bit 0
+            ppu.write_register(LCDC, 0xD1);
+            run_lines(&mut ppu, 8);
+            //WY only has to have matched once this frame
+            ppu.write_register(WY, 255);
+            ppu.write_register(LCDC, 0xF1);
+            run_lines(&mut ppu, 8);
+            ppu.write_register(LCDC, 0xF0);
+            run_lines(&mut ppu, 8);
+            ppu.write_register(LCDC, 0xF1);
+            run_lines(&mut ppu, 154 - 52);
+            assert_eq!(shade(&ppu, 0, 19), 0);
+            assert_eq!(shade(&ppu, 0, 20), 1);
+            assert_eq!(shade(&ppu, 0, 27), 1);
+            assert_eq!(shade(&ppu, 0, 28), 0);
+            assert_eq!(shade(&ppu, 0, 35), 0);
+            assert_eq!(shade(&ppu, 0, 36), 2);
+            assert_eq!(shade(&ppu, 0, 43), 2);
+            assert_eq!(shade(&ppu, 0, 44), 0);
+            assert_eq!(shade(&ppu, 0, 52), 3);
+            assert_eq!(shade(&ppu, 0, 60), 1);
+        }
+    }
+
+    #[test]
+    fn window_at_the_screen_edges()
+    {
+        for renderer in [Renderer::Scanline, Renderer::PixelFifo]
+        {
+            let mut ppu = ppu(0xF1);
+            ppu.set_renderer(renderer);
+            ppu.write_to_vram(TILE_MAP_9C00, 3);
+            ppu.write_to_vram(TILE_MAP_9C00 + 1, 1);
+            //WX 0-6 cut the left of the window off
+            ppu.write_register(WX, 3);
+            draw(&mut ppu);
+            assert_eq!(shade(&ppu, 0, 0), 3);
+            assert_eq!(shade(&ppu, 3, 0), 3);
+            assert_eq!(shade(&ppu, 4, 0), 1);
+            //WX 166 shows one column on the line WY matches, then covers the whole of every line after it
+            ppu.write_register(WY, 10);
+            ppu.write_register(WX, 166);
+            draw(&mut ppu);
+            assert_eq!(shade(&ppu, 0, 9), 0);
+            assert_eq!(shade(&ppu, 158, 10), 0);
+            assert_eq!(shade(&ppu, 159, 10), 3);
+            assert_eq!(shade(&ppu, 0, 11), 3);
+            assert_eq!(shade(&ppu, 8, 11), 1);
+            ppu.write_register(WX, 167);
+            draw(&mut ppu);
+            assert_eq!(shade(&ppu, 159, 10), 0);
+            assert_eq!(shade(&ppu, 0, 11), 0);
+        }
+    }
+
+    //Dots line 0 spends in mode 3 going by STAT, with the pixel FIFO
+    fn mode_3_length(lcdc: u8, setup: impl Fn(&mut PPU)) -> usize
+    {
+        let mut ppu = ppu(lcdc);
+        ppu.set_renderer(Renderer::PixelFifo);
+        setup(&mut ppu);
+        let mut interrupts = InterruptFlags::new();
+        let mut length = 0;
+        for _ in 0..LINE_CYCLES
+        {
+            ppu.step(1, &mut interrupts);
+            if (ppu.read_register(STAT) & 0x03) == 3
+            {
+                length += 1;
+            }
+        }
+        length
+    }
+
+    #[test]
+    fn fine_scroll_and_window_lengthen_mode_3()
+    {
+        assert_eq!(mode_3_length(0x91, |_| {}), 172);
+        for scx in [1, 7, 8, 11]
+        {
+            assert_eq!(mode_3_length(0x91, |ppu| ppu.write_register(SCX, scx)), 172 + scx as usize % 8);
+        }
+        assert_eq!(mode_3_length(0xB1, |ppu| ppu.write_register(WX, 50)), 178);
+        //Off the right edge it never starts
+        assert_eq!(mode_3_length(0xB1, |ppu| ppu.write_register(WX, 167)), 172);
+    }
+
+    #[test]
+    fn object_fetches_lengthen_mode_3()
+    {
+        //6 dots, plus 0-5 waiting on the background tile the object starts in
+        for (x, length) in [(0, 183), (8, 183), (9, 183), (50, 182), (54, 178), (167, 178)]
+        {
+            assert_eq!(mode_3_length(0x93, |ppu| object(ppu, 0, 16, x, 1, 0x00)), length);
+        }
+        assert_eq!(mode_3_length(0x93, |ppu| { ppu.write_register(SCX, 2); object(ppu, 0, 16, 50, 1, 0x00); }), 182);
+        //Only the first object in a tile waits
+        assert_eq!(mode_3_length(0x93, |ppu| { object(ppu, 0, 16, 50, 1, 0x00); object(ppu, 1, 16, 52, 1, 0x00); }), 188);
+        assert_eq!(mode_3_length(0x93, |ppu| { object(ppu, 0, 16, 50, 1, 0x00); object(ppu, 1, 16, 60, 1, 0x00); }), 190);
+        //With LCDC bit 1 off objects aren't fetched at all
+        assert_eq!(mode_3_length(0x91, |ppu| object(ppu, 0, 16, 50, 1, 0x00)), 172);
+        //HBlank takes up the rest of the line
+        let mut ppu = ppu(0x93);
+        ppu.set_renderer(Renderer::PixelFifo);
+        object(&mut ppu, 0, 16, 50, 1, 0x00);
+        run_lines(&mut ppu, 1);
+        assert_eq!(ppu.read_register(LY), 1);
+        assert_eq!(ppu.read_register(STAT) & 0x03, 2);
+    }
+
+    #[test]
+    fn fifo_draws_the_same_frame_as_the_scanline_renderer()
+    {
+        let frames = [Renderer::Scanline, Renderer::PixelFifo].map(|renderer|
+        {
+            let mut ppu = ppu(0xF3);
+            ppu.set_renderer(renderer);
+            for tile in 4..8
+            {
+                for byte in 0..16
+                {
+                    ppu.write_to_vram(tile * 16 + byte, (tile * 37 + byte * 11) as u8);
+                }
+            }
+            for index in 0..0x400
+            {
+                ppu.write_to_vram(TILE_MAP_9800 + index, (index * 7 % 8) as u8);
+                ppu.write_to_vram(TILE_MAP_9C00 + index, (index * 5 % 8) as u8);
+            }
+            ppu.write_register(SCX, 3);
+            ppu.write_register(SCY, 5);
+            ppu.write_register(WY, 40);
+            ppu.write_register(WX, 50);
+            ppu.write_register(OBP1, 0x1B);
+            //Overlapping, flipped, behind the background and partly off each edge
+            object(&mut ppu, 0, 20, 30, 5, 0x00);
+            object(&mut ppu, 1, 22, 34, 6, 0x20);
+            object(&mut ppu, 2, 44, 60, 7, 0x80);
+            object(&mut ppu, 3, 46, 62, 4, 0x50);
+            object(&mut ppu, 4, 10, 4, 5, 0x00);
+            object(&mut ppu, 5, 150, 164, 6, 0x60);
+            draw(&mut ppu);
+            ppu.frame_buffer().to_vec()
+        });
+        assert!(frames[0] == frames[1]);
+        assert!(frames[0].iter().any(|&colour| colour != frames[0][0]));
+    }
+
+    #[test]
+    fn lcd_off_stops_in_mode_0_at_ly_0()
+    {
+        let mut ppu = ppu(0x91);
+        run_lines(&mut ppu, 50);
+        assert_eq!(ppu.read_register(LY), 50);
+        ppu.write_register(LCDC, 0x11);
+        assert_eq!(ppu.read_register(LY), 0);
+        assert_eq!(ppu.read_register(STAT) & 0x03, 0);
+        assert!(ppu.frame_buffer().iter().all(|&colour| colour == BLANK));
+        //The clock's stopped
+        let mut interrupts = InterruptFlags::new();
+        for _ in 0..1000
+        {
+            ppu.step(4, &mut interrupts);
+        }
+        assert_eq!(ppu.read_register(LY), 0);
+        assert_eq!(interrupts.to_byte(), 0);
+        ppu.write_register(LCDC, 0x91);
+        assert_eq!(ppu.read_register(LY), 0);
+        assert_eq!(ppu.read_register(STAT) & 0x03, 2);
+    }
+
+    #[test]
+    fn first_frame_after_lcd_on_is_skipped()
+    {
+        let mut ppu = ppu(0x91);
+        for index in 0..0x400
+        {
+            ppu.write_to_vram(TILE_MAP_9800 + index, 3);
+        }
+        for _ in 0..2
+        {
+            run_lines(&mut ppu, 154);
+            assert!(ppu.frame_buffer().iter().all(|&colour| colour == BLANK));
+            run_lines(&mut ppu, 154);
+            assert!(ppu.frame_buffer().iter().all(|&colour| colour == 0));
+            ppu.write_register(LCDC, 0x11);
+            assert!(ppu.frame_buffer().iter().all(|&colour| colour == BLANK));
+            ppu.write_register(LCDC, 0x91);
+        }
+    }
+
+    //STAT interrupts requested on each of the frame's lines, with line 0 starting at the second frame
+    fn stat_interrupts_per_line(ppu: &mut PPU) -> [usize; 154]
+    {
+        run_lines(ppu, 154);
+        let mut lines = [0; 154];
+        for _ in 0..(154 * LINE_CYCLES as usize / 4)
+        {
+            let mut interrupts = InterruptFlags::new();
+            ppu.step(4, &mut interrupts);
+            if interrupts.lcdstat
+            {
+                lines[ppu.read_register(LY) as usize] += 1;
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn vblank_starts_at_ly_144()
+    {
+        let mut ppu = ppu(0x91);
+        let mut interrupts = InterruptFlags::new();
+        let mut cycles = 0;
+        while !interrupts.vblank
+        {
+            ppu.step(4, &mut interrupts);
+            cycles += 4;
+        }
+        //Whether or not STAT asks for it
+        assert_eq!(cycles, 144 * LINE_CYCLES as usize);
+        assert_eq!(ppu.read_register(LY), 144);
+        assert_eq!(ppu.read_register(STAT) & 0x03, 1);
+        assert!(!interrupts.lcdstat);
+        run_lines(&mut ppu, 10);
+        assert_eq!(ppu.read_register(LY), 0);
+        assert_eq!(ppu.read_register(STAT) & 0x03, 2);
+    }
+
+    #[test]
+    fn stat_sources_share_one_interrupt_line()
+    {
+        //HBlank runs straight into the next line's OAM scan, so that one's lost. Line 0's comes after VBlank.
+        let mut ppu = ppu(0x91);
+        ppu.write_register(STAT, 0x28);
+        let lines = stat_interrupts_per_line(&mut ppu);
+        assert_eq!(lines[0], 2);
+        assert!(lines[1..144].iter().all(|&count| count == 1));
+        assert!(lines[144..].iter().all(|&count| count == 0));
+        //Line 5's LYC match starts while line 4's HBlank is holding the line up and lasts through its own HBlank
+        ppu.write_register(STAT, 0x48);
+        ppu.write_register(LYC, 5);
+        let lines = stat_interrupts_per_line(&mut ppu);
+        assert_eq!(lines[4..7], [1, 0, 1]);
+        ppu.write_register(STAT, 0x08);
+        let lines = stat_interrupts_per_line(&mut ppu);
+        assert_eq!(lines[4..7], [1, 1, 1]);
+        ppu.write_register(STAT, 0x40);
+        let lines = stat_interrupts_per_line(&mut ppu);
+        assert_eq!(lines[4..7], [0, 1, 0]);
+        //VBlank and the LYC match on line 144 together are one interrupt
+        ppu.write_register(STAT, 0x50);
+        ppu.write_register(LYC, 144);
+        let lines = stat_interrupts_per_line(&mut ppu);
+        assert_eq!(lines.iter().sum::<usize>(), 1);
+        assert_eq!(lines[144], 1);
+    }
+
+    #[test]
+    fn dmg_stat_write_requests_an_interrupt()
+    {
+        let mut ppu = ppu(0x91);
+        ppu.write_register(LYC, 100);
+        let mut interrupts = InterruptFlags::new();
+        //Mode 3 with no LYC match, nothing for the write to set off
+        for _ in 0..21
+        {
+            ppu.step(4, &mut interrupts);
+        }
+        assert_eq!(ppu.read_register(STAT) & 0x03, 3);
+        ppu.write_register(STAT, 0x00);
+        ppu.step(4, &mut interrupts);
+        assert!(!interrupts.lcdstat);
+        ppu.write_register(LYC, 0);
+        ppu.write_register(STAT, 0x00);
+        ppu.step(4, &mut interrupts);
+        assert!(interrupts.lcdstat);
+        //Not while a selected source is already holding the line up
+        ppu.write_register(STAT, 0x40);
+        ppu.step(4, &mut interrupts);
+        interrupts.lcdstat = false;
+        ppu.write_register(STAT, 0x40);
+        ppu.step(4, &mut interrupts);
+        assert!(!interrupts.lcdstat);
+        //HBlank
+        ppu.write_register(LYC, 100);
+        ppu.write_register(STAT, 0x00);
+        ppu.step(4, &mut interrupts);
+        interrupts.lcdstat = false;
+        for _ in 0..45
+        {
+            ppu.step(4, &mut interrupts);
+        }
+        assert_eq!(ppu.read_register(STAT) & 0x03, 0);
+        assert!(!interrupts.lcdstat);
+        ppu.write_register(STAT, 0x00);
+        ppu.step(4, &mut interrupts);
+        assert!(interrupts.lcdstat);
+        //Never with the LCD off
+        interrupts.lcdstat = false;
+        ppu.write_register(LCDC, 0x11);
+        ppu.write_register(STAT, 0x00);
+        ppu.step(4, &mut interrupts);
+        assert!(!interrupts.lcdstat);
+    }
+}
@@ -0,0 +1,353 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::CPU_CLOCK_HZ;
+
+//Register numbers as written to the MBC3 RAM bank register
+pub const RTC_SECONDS: u8 = 0x08;
+pub const RTC_MINUTES: u8 = 0x09;
+pub const RTC_HOURS: u8 = 0x0A;
+pub const RTC_DAY_LOW: u8 = 0x0B;
+pub const RTC_DAY_HIGH: u8 = 0x0C;
+
+//Save file footer used by VBA-M, BGB, mGBA and most others: the live S, M, H, DL, DH registers, then the latched ones,
+//each as a little endian u32, then the unix time of the save. Older saves only have 32 bits of timestamp.
+pub const RTC_FOOTER_SIZE: usize = 48;
+pub const RTC_FOOTER_SIZE_SHORT: usize = 44;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+//The day counter is 9 bits
+const DAY_LIMIT: u64 = 512;
+
+pub fn host_time() -> u64
+{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource
+{
+    //Counts the cycles the emulator runs, so time stops when the emulator does
+    Emulated,
+    //Follows the host's wall clock, caught up whenever the game latches or writes the clock
+    Host,
+}
+
+//The MBC3's real time clock: live counters, the latched copy the game reads, and the sub-second prescaler
+#[derive(Clone)]
+pub struct RealTimeClock
+{
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16,
+    pub halted: bool,
+    pub day_carry: bool,
+    //S, M, H, DL, DH as they were at the last latch
+    pub latched: [u8; 5],
+    pub source: ClockSource,
+    //Unix time the counters were last brought up to date with the host
+    pub timestamp: u64,
+    cycles: u32,
+}
+impl RealTimeClock
+{
+    pub fn new() -> RealTimeClock
+    {
+        RealTimeClock
+        {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halted: false,
+            day_carry: false,
+            latched: [0; 5],
+            source: ClockSource::Emulated,
+            timestamp: host_time(),
+            cycles: 0,
+        }
+    }
+    pub fn step(&mut self, cycles: u8)
+    {
+        if self.halted || self.source == ClockSource::Host
+        {
+            return;
+        }
+        self.cycles += cycles as u32;
+        if self.cycles >= CPU_CLOCK_HZ
+        {
+            self.cycles -= CPU_CLOCK_HZ;
+            self.tick();
+        }
+    }
+    //One second. The counters are 6/6/5 bits wide, so a value the game wrote out of range counts up to
+    //the top of the register and wraps to 0 without carrying into the next one.
+    fn tick(&mut self)
+    {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60
+        {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60
+        {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24
+        {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days as u64 == DAY_LIMIT
+        {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+    fn in_range(&self) -> bool
+    {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+    //Moves the clock on by a number of seconds, used when catching up with the host
+    pub fn advance(&mut self, mut seconds: u64)
+    {
+        if self.halted
+        {
+            return;
+        }
+        //Tick out of any invalid state one second at a time, after that it's just arithmetic
+        while seconds > 0 && !self.in_range()
+        {
+            self.tick();
+            seconds -= 1;
+        }
+        if seconds == 0
+        {
+            return;
+        }
+        let total = self.days as u64 * SECONDS_PER_DAY + self.hours as u64 * 3600 + self.minutes as u64 * 60 + self.seconds as u64 + seconds;
+        let days = total / SECONDS_PER_DAY;
+        if days >= DAY_LIMIT
+        {
+            self.day_carry = true;
+        }
+        self.days = (days % DAY_LIMIT) as u16;
+        self.hours = ((total % SECONDS_PER_DAY) / 3600) as u8;
+        self.minutes = ((total % 3600) / 60) as u8;
+        self.seconds = (total % 60) as u8;
+    }
+    //Catch up with however long the host says has passed since the last sync
+    pub fn sync_with_host(&mut self)
+    {
+        let now = host_time();
+        self.advance(now.saturating_sub(self.timestamp));
+        self.timestamp = now;
+    }
+    fn day_high(&self) -> u8
+    {
+        ((self.day_carry as u8) << 7) | ((self.halted as u8) << 6) | ((self.days >> 8) as u8 & 0x01)
+    }
+    pub fn latch(&mut self)
+    {
+        if self.source == ClockSource::Host
+        {
+            self.sync_with_host();
+        }
+        self.latched = [self.seconds, self.minutes, self.hours, self.days as u8, self.day_high()];
+    }
+    pub fn to_footer(&self) -> Vec<u8>
+    {
+        let live = [self.seconds, self.minutes, self.hours, self.days as u8, self.day_high()];
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for register in live.iter().chain(self.latched.iter())
+        {
+            footer.extend_from_slice(&(*register as u32).to_le_bytes());
+        }
+        //A host clock is only up to date as of its timestamp, an emulated one is up to date now
+        let timestamp = match self.source
+        {
+            ClockSource::Host => self.timestamp,
+            ClockSource::Emulated => host_time(),
+        };
+        footer.extend_from_slice(&timestamp.to_le_bytes());
+        footer
+    }
+    //Returns false and leaves the clock alone if the footer isn't one of the two known sizes
+    pub fn load_footer(&mut self, footer: &[u8]) -> bool
+    {
+        if footer.len() != RTC_FOOTER_SIZE && footer.len() != RTC_FOOTER_SIZE_SHORT
+        {
+            return false;
+        }
+        let register = |i: usize| footer[i * 4];
+        self.seconds = register(0) & 0x3F;
+        self.minutes = register(1) & 0x3F;
+        self.hours = register(2) & 0x1F;
+        self.days = (((register(4) & 0x01) as u16) << 8) | register(3) as u16;
+        self.halted = (register(4) & 0x40) != 0;
+        self.day_carry = (register(4) & 0x80) != 0;
+        for i in 0..5
+        {
+            self.latched[i] = register(5 + i);
+        }
+        let mut timestamp = [0; 8];
+        timestamp[..footer.len() - 40].copy_from_slice(&footer[40..]);
+        //Host mode catches up on the time spent switched off the next time the game looks at the clock
+        self.timestamp = u64::from_le_bytes(timestamp);
+        self.cycles = 0;
+        true
+    }
+    //Unused bits read back as 1
+    pub fn read(&self, register: u8) -> u8
+    {
+        match register
+        {
+            RTC_SECONDS => self.latched[0] | 0xC0,
+            RTC_MINUTES => self.latched[1] | 0xC0,
+            RTC_HOURS => self.latched[2] | 0xE0,
+            RTC_DAY_LOW => self.latched[3],
+            RTC_DAY_HIGH => self.latched[4] | 0x3E,
+            _ => 0xFF,
+        }
+    }
+    pub fn write(&mut self, register: u8, value: u8)
+    {
+        if self.source == ClockSource::Host
+        {
+            self.sync_with_host();
+        }
+        match register
+        {
+            RTC_SECONDS =>
+            {
+                self.seconds = value & 0x3F;
+                //Writing the seconds also restarts the current second
+                self.cycles = 0;
+            },
+            RTC_MINUTES => self.minutes = value & 0x3F,
+            RTC_HOURS => self.hours = value & 0x1F,
+            RTC_DAY_LOW => self.days = (self.days & 0x100) | value as u16,
+            RTC_DAY_HIGH =>
+            {
+                self.days = (self.days & 0xFF) | (((value & 0x01) as u16) << 8);
+                self.halted = (value & 0x40) != 0;
+                self.day_carry = (value & 0x80) != 0;
+            },
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn counts_emulated_seconds()
+    {
+        let mut rtc = RealTimeClock::new();
+        rtc.step(200);
+        for _ in 0..CPU_CLOCK_HZ / 200
+        {
+            rtc.step(200);
+        }
+        assert_eq!(rtc.seconds, 1);
+        //Halted clocks don't count
+        rtc.halted = true;
+        for _ in 0..CPU_CLOCK_HZ / 100
+        {
+            rtc.step(200);
+        }
+        assert_eq!(rtc.seconds, 1);
+    }
+
+    #[test]
+    fn rolls_over_into_days()
+    {
+        let mut rtc = RealTimeClock::new();
+        rtc.hours = 23;
+        rtc.minutes = 59;
+        rtc.seconds = 59;
+        rtc.days = 0x1FF;
+        rtc.advance(1);
+        assert_eq!((rtc.days, rtc.hours, rtc.minutes, rtc.seconds), (0, 0, 0, 0));
+        assert!(rtc.day_carry);
+        rtc.day_carry = false;
+        rtc.advance(SECONDS_PER_DAY * 3 + 3600 + 61);
+        assert_eq!((rtc.days, rtc.hours, rtc.minutes, rtc.seconds), (3, 1, 1, 1));
+        assert!(!rtc.day_carry);
+    }
+
+    #[test]
+    fn out_of_range_values_wrap_without_carrying()
+    {
+        let mut rtc = RealTimeClock::new();
+        rtc.write(RTC_SECONDS, 63);
+        rtc.advance(1);
+        assert_eq!((rtc.minutes, rtc.seconds), (0, 0));
+        rtc.write(RTC_HOURS, 31);
+        rtc.advance(3600);
+        assert_eq!((rtc.days, rtc.hours), (0, 0));
+    }
+
+    #[test]
+    fn latch_copies_registers()
+    {
+        let mut rtc = RealTimeClock::new();
+        rtc.write(RTC_MINUTES, 5);
+        rtc.write(RTC_DAY_LOW, 0x34);
+        rtc.write(RTC_DAY_HIGH, 0xC1);
+        assert_eq!(rtc.read(RTC_MINUTES), 0xC0);
+        rtc.latch();
+        assert_eq!(rtc.read(RTC_MINUTES), 0xC5);
+        assert_eq!(rtc.read(RTC_DAY_LOW), 0x34);
+        assert_eq!(rtc.read(RTC_DAY_HIGH), 0xFF);
+        assert_eq!(rtc.days, 0x134);
+        assert!(rtc.halted && rtc.day_carry);
+        //Later changes don't show until the next latch
+        rtc.write(RTC_MINUTES, 6);
+        assert_eq!(rtc.read(RTC_MINUTES), 0xC5);
+    }
+
+    #[test]
+    fn footer_round_trip()
+    {
+        let mut rtc = RealTimeClock::new();
+        rtc.write(RTC_SECONDS, 12);
+        rtc.write(RTC_HOURS, 7);
+        rtc.write(RTC_DAY_HIGH, 0x81);
+        rtc.latch();
+        rtc.write(RTC_MINUTES, 34);
+        rtc.source = ClockSource::Host;
+        rtc.timestamp = 0x1_2345_6789;
+        let footer = rtc.to_footer();
+        assert_eq!(footer.len(), RTC_FOOTER_SIZE);
+
+        let mut loaded = RealTimeClock::new();
+        assert!(loaded.load_footer(&footer));
+        assert_eq!((loaded.seconds, loaded.minutes, loaded.hours, loaded.days), (12, 34, 7, 0x100));
+        assert!(loaded.day_carry && !loaded.halted);
+        assert_eq!(loaded.latched, rtc.latched);
+        assert_eq!(loaded.timestamp, 0x1_2345_6789);
+    }
+
+    #[test]
+    fn short_and_unknown_footers()
+    {
+        let mut footer = RealTimeClock::new().to_footer();
+        footer[0] = 30;
+        footer[44..].fill(0);
+        footer.truncate(RTC_FOOTER_SIZE_SHORT);
+        let mut rtc = RealTimeClock::new();
+        assert!(rtc.load_footer(&footer));
+        assert_eq!(rtc.seconds, 30);
+        assert!(!rtc.load_footer(&footer[..40]));
+        assert_eq!(rtc.seconds, 30);
+    }
+}
@@ -0,0 +1,238 @@
+use std::f64::consts::PI;
+
+//The APU is sampled once per M-cycle
+pub const INPUT_RATE: f64 = crate::CPU_CLOCK_HZ as f64 / 4.0;
+
+//Each change in level is spread over this many output samples as a band-limited step, at one of PHASES
+//sub-sample offsets
+const KERNEL_WIDTH: usize = 32;
+const KERNEL_PHASES: usize = 64;
+//Just under the output Nyquist frequency, as a fraction of the output rate
+const CUTOFF: f64 = 0.45;
+
+//How far dynamic rate control can pull the output rate either way, small enough not to be heard as pitch
+const MAX_RATE_ADJUST: f64 = 0.005;
+
+//How much charge the DMG's output capacitor keeps each T-cycle
+const CAPACITOR_CHARGE: f64 = 0.999958;
+
+//-1.0 to 1.0 into 16 bit PCM, clipping anything past full scale
+pub fn to_i16(frame: [f32; 2]) -> [i16; 2]
+{
+    frame.map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+}
+
+//Windowed sinc impulses for every phase, each summing to 1 so a step of size delta always settles at delta
+fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]>
+{
+    let mut kernel = vec![[0.0; KERNEL_WIDTH]; KERNEL_PHASES];
+    for (phase, taps) in kernel.iter_mut().enumerate()
+    {
+        let offset = phase as f64 / KERNEL_PHASES as f64;
+        let mut sum = 0.0;
+        let mut impulse = [0.0f64; KERNEL_WIDTH];
+        for (tap, value) in impulse.iter_mut().enumerate()
+        {
+            let distance = tap as f64 - offset - (KERNEL_WIDTH / 2) as f64;
+            let sinc = if distance == 0.0 {1.0} else {(2.0 * PI * CUTOFF * distance).sin() / (2.0 * PI * CUTOFF * distance)};
+            //Blackman window
+            let window = 0.42 + 0.5 * (2.0 * PI * distance / KERNEL_WIDTH as f64).cos() + 0.08 * (4.0 * PI * distance / KERNEL_WIDTH as f64).cos();
+            *value = sinc * window;
+            sum += *value;
+        }
+        for (tap, value) in taps.iter_mut().enumerate()
+        {
+            *value = (impulse[tap] / sum) as f32;
+        }
+    }
+    kernel
+}
+
+//Turns the APU's stereo level, sampled every M-cycle, into frames at the host's rate. Rather than picking every
+//nth input, each change in level is added to the output as a band-limited step so the square waves don't alias.
+//The buffers hold the differences between samples, which get summed back up as frames are read out.
+pub struct Resampler
+{
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    output_rate: u32,
+    //Output samples per input sample, before and after dynamic rate control
+    base_ratio: f64,
+    ratio: f64,
+    //Position of the next input sample, in output samples from the start of the buffers
+    time: f64,
+    deltas: [Vec<f32>; 2],
+    //The last level seen on each side, and what the integrated output is at the start of the buffers
+    input: [f32; 2],
+    level: [f32; 2],
+    //DMG high-pass filter, the charge left on each side's capacitor
+    capacitor: [f32; 2],
+    capacitor_charge: f32,
+}
+impl Resampler
+{
+    pub fn new(output_rate: u32) -> Resampler
+    {
+        let base_ratio = output_rate as f64 / INPUT_RATE;
+        Resampler
+        {
+            kernel: build_kernel(),
+            output_rate,
+            base_ratio,
+            ratio: base_ratio,
+            time: 0.0,
+            deltas: [vec![0.0; KERNEL_WIDTH], vec![0.0; KERNEL_WIDTH]],
+            input: [0.0; 2],
+            level: [0.0; 2],
+            capacitor: [0.0; 2],
+            capacitor_charge: CAPACITOR_CHARGE.powf(crate::CPU_CLOCK_HZ as f64 / output_rate as f64) as f32,
+        }
+    }
+    pub fn output_rate(&self) -> u32
+    {
+        self.output_rate
+    }
+    //One M-cycle of APU output
+    pub fn push(&mut self, left: f32, right: f32)
+    {
+        let index = self.time as usize;
+        let phase = ((self.time - index as f64) * KERNEL_PHASES as f64) as usize;
+        for (side, sample) in [left, right].into_iter().enumerate()
+        {
+            let delta = sample - self.input[side];
+            if delta == 0.0
+            {
+                continue;
+            }
+            self.input[side] = sample;
+            let buffer = &mut self.deltas[side];
+            if buffer.len() < index + KERNEL_WIDTH
+            {
+                buffer.resize(index + KERNEL_WIDTH, 0.0);
+            }
+            for (tap, weight) in self.kernel[phase].iter().enumerate()
+            {
+                buffer[index + tap] += delta * weight;
+            }
+        }
+        self.time += self.ratio;
+    }
+    //How many frames are ready to read, anything later could still be changed by steps yet to come
+    pub fn available(&self) -> usize
+    {
+        self.time as usize
+    }
+    //Move every finished frame into out, -1.0 to 1.0
+    pub fn read_f32(&mut self, out: &mut Vec<[f32; 2]>)
+    {
+        let count = self.available();
+        out.reserve(count);
+        for index in 0..count
+        {
+            let mut frame = [0.0; 2];
+            for (side, value) in frame.iter_mut().enumerate()
+            {
+                self.level[side] += self.deltas[side].get(index).copied().unwrap_or(0.0);
+                //The capacitor slowly charges up to whatever DC offset the DACs are putting out and takes it away
+                let filtered = self.level[side] - self.capacitor[side];
+                self.capacitor[side] = self.level[side] - filtered * self.capacitor_charge;
+                *value = filtered;
+            }
+            out.push(frame);
+        }
+        for buffer in &mut self.deltas
+        {
+            buffer.drain(..count.min(buffer.len()));
+            if buffer.len() < KERNEL_WIDTH
+            {
+                buffer.resize(KERNEL_WIDTH, 0.0);
+            }
+        }
+        self.time -= count as f64;
+    }
+    pub fn read_i16(&mut self, out: &mut Vec<[i16; 2]>)
+    {
+        let mut frames = Vec::new();
+        self.read_f32(&mut frames);
+        out.extend(frames.into_iter().map(to_i16));
+    }
+    //Dynamic rate control. Given how many frames the host has queued and how many it wants queued, make slightly
+    //more output when running low and slightly less when backing up, so the two clocks drifting apart never
+    //runs the host dry.
+    pub fn adjust_rate(&mut self, buffered: usize, target: usize)
+    {
+        if target == 0
+        {
+            return;
+        }
+        let error = ((target as f64 - buffered as f64) / target as f64).clamp(-1.0, 1.0);
+        self.ratio = self.base_ratio * (1.0 + MAX_RATE_ADJUST * error);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    //Pushes a second of input and returns everything it came out as
+    fn resample(resampler: &mut Resampler, input: impl Fn(usize) -> f32) -> Vec<[f32; 2]>
+    {
+        for index in 0..INPUT_RATE as usize
+        {
+            let sample = input(index);
+            resampler.push(sample, sample);
+        }
+        let mut out = Vec::new();
+        resampler.read_f32(&mut out);
+        out
+    }
+
+    #[test]
+    fn produces_output_rate()
+    {
+        let mut resampler = Resampler::new(48000);
+        let frames = resample(&mut resampler, |_| 0.0);
+        assert!((47999..=48000).contains(&frames.len()), "{}", frames.len());
+        assert_eq!(resampler.available(), 0);
+    }
+
+    #[test]
+    fn high_pass_removes_dc()
+    {
+        let mut resampler = Resampler::new(48000);
+        let frames = resample(&mut resampler, |_| 0.5);
+        assert!(frames[0..100].iter().any(|frame| frame[0] > 0.25));
+        assert!(frames[frames.len() - 100..].iter().all(|frame| frame[0].abs() < 0.001));
+    }
+
+    #[test]
+    fn square_wave_keeps_its_level()
+    {
+        //1 kHz square wave, well under the cutoff
+        let mut resampler = Resampler::new(48000);
+        let half_period = INPUT_RATE as usize / 2000;
+        let frames = resample(&mut resampler, |index| if (index / half_period).is_multiple_of(2) {0.5} else {-0.5});
+        let peak = frames[24000..].iter().map(|frame| frame[0].abs()).fold(0.0, f32::max);
+        //Band-limited edges ring a little past the level, and the high-pass tilts each half cycle
+        assert!(peak > 0.45 && peak < 0.7, "{}", peak);
+    }
+
+    #[test]
+    fn rate_control_follows_the_queue()
+    {
+        let mut resampler = Resampler::new(48000);
+        resampler.adjust_rate(0, 1000);
+        let running_low = resample(&mut resampler, |_| 0.0).len();
+        resampler.adjust_rate(2000, 1000);
+        let backing_up = resample(&mut resampler, |_| 0.0).len();
+        assert!((48239..=48240).contains(&running_low), "{}", running_low);
+        assert!((47759..=47760).contains(&backing_up), "{}", backing_up);
+    }
+
+    #[test]
+    fn i16_conversion_clips()
+    {
+        assert_eq!(to_i16([0.0, 1.0]), [0, i16::MAX]);
+        assert_eq!(to_i16([2.0, -2.0]), [i16::MAX, -i16::MAX]);
+    }
+}
@@ -1,3 +1,9 @@
+pub const DIV: usize = 0xFF04;
+pub const TIMA: usize = 0xFF05;
+pub const TMA: usize = 0xFF06;
+pub const TAC: usize = 0xFF07;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Frequency
 {
     //Different timer frequencies available on gameboy
@@ -9,67 +15,246 @@ pub enum Frequency
 
 impl Frequency
 {
-    //how many cycles makes the timer tick over
-    fn cycles_per_tick(&self) -> usize
+    fn from_bits(bits: u8) -> Frequency
+    {
+        match bits & 0b11
+        {
+            0b00 => Frequency::F4096,
+            0b01 => Frequency::F262144,
+            0b10 => Frequency::F65536,
+            _ => Frequency::F16384,
+        }
+    }
+    fn bits(&self) -> u8
     {
         match self
         {
-            Frequency::F4096 => 1024,
-            Frequency::F16384 => 256,
-            Frequency::F65536 => 64,
-            Frequency::F262144 => 16,
+            Frequency::F4096 => 0b00,
+            Frequency::F262144 => 0b01,
+            Frequency::F65536 => 0b10,
+            Frequency::F16384 => 0b11,
+        }
+    }
+    //TIMA ticks when this bit of the system counter goes from 1 to 0
+    fn counter_bit(&self) -> u16
+    {
+        match self
+        {
+            Frequency::F4096 => 1 << 9,
+            Frequency::F16384 => 1 << 7,
+            Frequency::F65536 => 1 << 5,
+            Frequency::F262144 => 1 << 3,
         }
     }
 }
 
+//DIV, TIMA, TMA and TAC all hang off one 16 bit counter that goes up every T-cycle. DIV is its top 8 bits and
+//TIMA counts falling edges of the TAC selected bit ANDed with the enable bit, which is why writing DIV or TAC
+//can tick TIMA on its own.
 pub struct Timer
 {
-    //Frequency for obvious reasons, value is timer value (duh), cycles is current cycles, modulo is what value is set to if there is overflow.
-    //Kinda like a baseline for the timer, like how Void Fiend from Risk of Rain 2 has a baseline on its corruption meter.
-    //Enabled flag for obvious reasons
+    pub counter: u16,
     pub frequency: Frequency,
     pub value: u8,
-    pub cycles: usize,
     pub modulo: u8,
-    pub enabled: bool
+    pub enabled: bool,
+    //TIMA reads 0 for an M-cycle after overflowing, then TMA gets loaded and the interrupt requested
+    overflowed: bool,
+    //The M-cycle TMA gets loaded on, TIMA writes are lost and TMA writes go straight through to TIMA
+    reloading: bool,
 }
 impl Timer
 {
-    pub fn new(freq: Frequency) -> Self
+    pub fn new() -> Self
     {
         Timer
         {
-            frequency: freq,
+            counter: 0,
+            frequency: Frequency::F4096,
             value: 0,
-            cycles: 0,
             modulo: 0,
             enabled: false,
+            overflowed: false,
+            reloading: false,
         }
     }
-    //Advance the timer by how many cycles the cpu just did with all them instructions n that
+    fn signal(&self) -> bool
+    {
+        self.enabled && (self.counter & self.frequency.counter_bit()) != 0
+    }
+    fn increment(&mut self)
+    {
+        let (value, overflow) = self.value.overflowing_add(1);
+        self.value = value;
+        self.overflowed = overflow;
+    }
+    //Advance by the cycles the CPU just spent, one M-cycle at a time. Returns true when the timer interrupt is requested.
     pub fn step(&mut self, cycles: u8) -> bool
     {
-        if !self.enabled
+        let mut interrupt = false;
+        for _ in 0..cycles / 4
         {
-            return false;
+            self.reloading = false;
+            if self.overflowed
+            {
+                self.overflowed = false;
+                self.reloading = true;
+                self.value = self.modulo;
+                interrupt = true;
+            }
+            let before = self.signal();
+            self.counter = self.counter.wrapping_add(4);
+            if before && !self.signal()
+            {
+                self.increment();
+            }
         }
-        self.cycles += cycles as usize;
-        let cpt = self.frequency.cycles_per_tick();
-        let overflowed = if self.cycles > cpt 
+        interrupt
+    }
+    pub fn divider(&self) -> u8
+    {
+        (self.counter >> 8) as u8
+    }
+    pub fn read(&self, address: usize) -> u8
+    {
+        match address
         {
-            self.cycles = self.cycles % cpt;
-            let (new, overflow) = self.value.overflowing_add(1);
-            self.value = new;
-            overflow
+            DIV => self.divider(),
+            TIMA => self.value,
+            TMA => self.modulo,
+            TAC => 0xF8 | (self.enabled as u8) << 2 | self.frequency.bits(),
+            _ => 0xFF,
         }
-        else
+    }
+    pub fn write(&mut self, address: usize, value: u8)
+    {
+        let before = self.signal();
+        match address
         {
-            return false;
-        };
-        if overflowed
+            //Any write resets the whole counter, not just DIV
+            DIV => self.counter = 0,
+            //Writing during the delay cancels the reload, writing on the reload cycle is lost
+            TIMA if !self.reloading =>
+            {
+                self.value = value;
+                self.overflowed = false;
+            },
+            TMA =>
+            {
+                self.modulo = value;
+                if self.reloading
+                {
+                    self.value = value;
+                }
+            },
+            TAC =>
+            {
+                self.enabled = (value & 0b100) != 0;
+                self.frequency = Frequency::from_bits(value);
+            },
+            _ => {},
+        }
+        //Pulling the selected bit low by hand counts as a falling edge too
+        if before && !self.signal()
         {
-            self.value = self.modulo;
+            self.increment();
         }
-        overflowed
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn tima_ticks_on_falling_edge()
+    {
+        //262144 Hz, TIMA counts falling edges of counter bit 3
+        let mut timer = Timer::new();
+        timer.write(TAC, 0b101);
+        timer.step(12);
+        assert_eq!(timer.read(TIMA), 0);
+        timer.step(4);
+        assert_eq!(timer.read(TIMA), 1);
+        timer.step(16);
+        assert_eq!(timer.read(TIMA), 2);
+    }
+
+    #[test]
+    fn overflow_reloads_one_m_cycle_late()
+    {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0b101);
+        timer.write(TMA, 0xAB);
+        timer.write(TIMA, 0xFF);
+        timer.counter = 12;
+        assert!(!timer.step(4));
+        assert_eq!(timer.read(TIMA), 0);
+        assert!(timer.step(4));
+        assert_eq!(timer.read(TIMA), 0xAB);
+    }
+
+    #[test]
+    fn tima_write_during_delay_cancels_reload()
+    {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0b101);
+        timer.write(TMA, 0xAB);
+        timer.write(TIMA, 0xFF);
+        timer.counter = 12;
+        timer.step(4);
+        timer.write(TIMA, 0x10);
+        assert!(!timer.step(4));
+        assert_eq!(timer.read(TIMA), 0x10);
+    }
+
+    #[test]
+    fn writes_on_reload_cycle()
+    {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0b101);
+        timer.write(TMA, 0xAB);
+        timer.write(TIMA, 0xFF);
+        timer.counter = 12;
+        timer.step(8);
+        //TIMA writes are lost, TMA writes go through to TIMA
+        timer.write(TIMA, 0x10);
+        assert_eq!(timer.read(TIMA), 0xAB);
+        timer.write(TMA, 0x42);
+        assert_eq!(timer.read(TIMA), 0x42);
+        //One M-cycle later TIMA writes stick again
+        timer.step(4);
+        timer.write(TIMA, 0x10);
+        assert_eq!(timer.read(TIMA), 0x10);
+    }
+
+    #[test]
+    fn div_write_glitch()
+    {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0b100);
+        timer.counter = 1 << 9;
+        timer.write(DIV, 0);
+        assert_eq!(timer.counter, 0);
+        assert_eq!(timer.read(TIMA), 1);
+        //With the bit low resetting DIV doesn't tick anything
+        timer.write(DIV, 0);
+        assert_eq!(timer.read(TIMA), 1);
+    }
+
+    #[test]
+    fn tac_write_glitch()
+    {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0b101);
+        timer.counter = 8;
+        //Disabling the timer while the selected bit is high pulls the signal low
+        timer.write(TAC, 0b001);
+        assert_eq!(timer.read(TIMA), 1);
+        //So does switching to a frequency whose bit is low
+        timer.write(TAC, 0b101);
+        timer.write(TAC, 0b110);
+        assert_eq!(timer.read(TIMA), 2);
+    }
+}
@@ -0,0 +1,179 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::APU::Channel;
+use crate::{GameBoy, Resampler};
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_SIZE: u32 = 44;
+//Where the two sizes that aren't known until the end live in the header
+const RIFF_SIZE_OFFSET: u64 = 4;
+const DATA_SIZE_OFFSET: u64 = 40;
+//RIFF sizes are 32 bit, which at 44100 Hz runs out after about six and a half hours
+const MAX_DATA_SIZE: u32 = u32::MAX - (HEADER_SIZE - 8);
+
+//16 bit stereo PCM, written as it comes in. The sizes in the header are filled in by finish.
+pub struct WavWriter<W: Write + Seek>
+{
+    writer: W,
+    data_size: u32,
+}
+impl<W: Write + Seek> WavWriter<W>
+{
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<WavWriter<W>>
+    {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        //1 is uncompressed PCM
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&CHANNELS.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { writer, data_size: 0 })
+    }
+    //Fails without writing anything once the file would grow past what its header can describe
+    pub fn write_frames(&mut self, frames: &[[i16; 2]]) -> io::Result<()>
+    {
+        let data_size = u32::try_from(frames.len() * 4).ok()
+            .and_then(|size| self.data_size.checked_add(size))
+            .filter(|&size| size <= MAX_DATA_SIZE)
+            .ok_or_else(|| io::Error::other("WAV file has reached its 4GB limit"))?;
+        for frame in frames
+        {
+            self.writer.write_all(&frame[0].to_le_bytes())?;
+            self.writer.write_all(&frame[1].to_le_bytes())?;
+        }
+        self.data_size = data_size;
+        Ok(())
+    }
+    pub fn finish(mut self) -> io::Result<W>
+    {
+        self.writer.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.writer.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+//out.wav becomes out.pulse1.wav and so on
+pub fn stem_path(path: &Path, channel: Channel) -> PathBuf
+{
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("{}.{}.wav", stem, channel.name()))
+}
+
+//Records everything a GameBoy plays to a .wav file, and with stems on one more per channel next to it.
+//Call record after every frame with the mix read out of the GameBoy, the stems are read out here.
+pub struct AudioRecorder
+{
+    mix: WavWriter<BufWriter<File>>,
+    stems: Vec<(Channel, WavWriter<BufWriter<File>>)>,
+    frames: Vec<[i16; 2]>,
+}
+impl AudioRecorder
+{
+    //Sets the GameBoy up to produce audio at sample_rate
+    pub fn create(gameboy: &mut GameBoy, path: &Path, sample_rate: u32, stems: bool) -> io::Result<AudioRecorder>
+    {
+        let mix = WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)?;
+        let mut stem_writers = Vec::new();
+        if stems
+        {
+            for channel in Channel::ALL
+            {
+                let file = File::create(stem_path(path, channel))?;
+                stem_writers.push((channel, WavWriter::new(BufWriter::new(file), sample_rate)?));
+            }
+        }
+        gameboy.set_sample_rate(Some(sample_rate));
+        gameboy.set_audio_stems(stems);
+        Ok(AudioRecorder { mix, stems: stem_writers, frames: Vec::new() })
+    }
+    pub fn record(&mut self, gameboy: &mut GameBoy, mix: &[[f32; 2]]) -> io::Result<()>
+    {
+        self.frames.clear();
+        self.frames.extend(mix.iter().copied().map(Resampler::to_i16));
+        self.mix.write_frames(&self.frames)?;
+        for (channel, writer) in &mut self.stems
+        {
+            self.frames.clear();
+            gameboy.read_stem_i16(*channel, &mut self.frames);
+            writer.write_frames(&self.frames)?;
+        }
+        Ok(())
+    }
+    pub fn finish(self) -> io::Result<()>
+    {
+        self.mix.finish()?;
+        for (_, writer) in self.stems
+        {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Cursor;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32
+    {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_header_and_frames()
+    {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 48000).unwrap();
+        writer.write_frames(&[[1, -1], [0x1234, -0x1234]]).unwrap();
+        writer.write_frames(&[[7, 8]]).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+        assert_eq!(bytes.len(), HEADER_SIZE as usize + 12);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), HEADER_SIZE - 8 + 12);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u16::from_le_bytes([bytes[22], bytes[23]]), CHANNELS);
+        assert_eq!(u32_at(&bytes, 24), 48000);
+        assert_eq!(u32_at(&bytes, 28), 48000 * 4);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 12);
+        assert_eq!(&bytes[44..48], &[0x01, 0x00, 0xFF, 0xFF]);
+        assert_eq!(&bytes[48..52], &[0x34, 0x12, 0xCC, 0xED]);
+    }
+
+    #[test]
+    fn stem_paths()
+    {
+        assert_eq!(stem_path(Path::new("music/out.wav"), Channel::Pulse1), Path::new("music/out.pulse1.wav"));
+        assert_eq!(stem_path(Path::new("out"), Channel::Noise), Path::new("out.noise.wav"));
+    }
+
+    #[test]
+    fn stops_at_riff_limit()
+    {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 48000).unwrap();
+        writer.data_size = MAX_DATA_SIZE - 4;
+        writer.write_frames(&[[0, 0]]).unwrap();
+        assert!(writer.write_frames(&[[0, 0]]).is_err());
+        //Nothing from the failed write makes it into the file, so the header still matches the data
+        let bytes = writer.finish().unwrap().into_inner();
+        assert_eq!(bytes.len(), HEADER_SIZE as usize + 4);
+        assert_eq!(u32_at(&bytes, 4), u32::MAX);
+    }
+}
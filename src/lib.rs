@@ -0,0 +1,294 @@
+//Modules and opcodes are named after the hardware (CPU, PPU, LD, HALT...) rather than Rust conventions
+#![allow(non_snake_case, clippy::upper_case_acronyms, clippy::new_without_default)]
+
+pub mod APU;
+pub mod Cartridge;
+pub mod CPU;
+pub mod GBS;
+pub mod Memory;
+pub mod PPU;
+pub mod RTC;
+pub mod InterruptFlags;
+pub mod Joypad;
+pub mod MBC;
+pub mod Model;
+pub mod Resampler;
+pub mod Timer;
+pub mod WAV;
+
+use Cartridge::CartridgeError;
+use Joypad::Buttons;
+
+//4194304 Hz clock over 154 lines of 456 cycles
+pub const CYCLES_PER_FRAME: u32 = 70224;
+pub const CPU_CLOCK_HZ: u32 = 4194304;
+
+#[derive(Debug)]
+pub enum GameBoyError
+{
+    //Boot ROMs are exactly 256 bytes
+    BootRomSize(usize),
+    Cartridge(CartridgeError),
+}
+impl std::fmt::Display for GameBoyError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            GameBoyError::BootRomSize(size) => write!(f, "boot ROM is {} bytes, it should be {}", size, Memory::BOOT_ROM_SIZE),
+            GameBoyError::Cartridge(error) => error.fmt(f),
+        }
+    }
+}
+impl std::error::Error for GameBoyError {}
+impl From<CartridgeError> for GameBoyError
+{
+    fn from(error: CartridgeError) -> Self
+    {
+        GameBoyError::Cartridge(error)
+    }
+}
+
+//Everything needed to run a game, for frontends and tools that just want to drive the emulator
+pub struct GameBoy
+{
+    //Without one the CPU starts at 0x0100 set up the way the model's boot ROM would leave it
+    boot_rom: Option<Vec<u8>>,
+    model: Model::Model,
+    cpu: CPU::CPU,
+    //Cycles already run into the current frame, instructions don't line up with frame boundaries
+    frame_cycles: u32,
+    //Told whenever a rumble cartridge switches its motor on or off
+    rumble_callback: Option<Box<dyn FnMut(bool)>>,
+    rumble: bool,
+}
+impl GameBoy
+{
+    pub fn new(boot_rom: Option<Vec<u8>>, game_rom: Vec<u8>, model: Model::Model) -> Result<GameBoy, GameBoyError>
+    {
+        if let Some(boot_rom) = boot_rom.as_ref().filter(|boot_rom| boot_rom.len() != Memory::BOOT_ROM_SIZE)
+        {
+            return Err(GameBoyError::BootRomSize(boot_rom.len()));
+        }
+        let cartridge = Cartridge::Cartridge::from_bytes(game_rom)?;
+        let cpu = CPU::CPU::new(boot_rom.clone(), cartridge, model);
+        Ok(GameBoy
+        {
+            boot_rom,
+            model,
+            cpu,
+            frame_cycles: 0,
+            rumble_callback: None,
+            rumble: false,
+        })
+    }
+    //Swap the cartridge and power cycle. A bad ROM leaves the current game running.
+    pub fn load_rom(&mut self, game_rom: Vec<u8>) -> Result<(), GameBoyError>
+    {
+        let cartridge = Cartridge::Cartridge::from_bytes(game_rom)?;
+        self.power_on(cartridge);
+        Ok(())
+    }
+    //Power cycle, keeping the same cartridge along with its save RAM and clock
+    pub fn reset(&mut self)
+    {
+        let mut cartridge = self.cpu.bus.cartridge.clone();
+        cartridge.reset();
+        self.power_on(cartridge);
+    }
+    //A fresh CPU and bus around the cartridge. Settings like the renderer belong to the emulator, not the
+    //machine, so they carry over.
+    fn power_on(&mut self, cartridge: Cartridge::Cartridge)
+    {
+        let renderer = self.cpu.bus.ppu.renderer();
+        let host_output = self.cpu.bus.apu.take_host_output();
+        self.cpu = CPU::CPU::new(self.boot_rom.clone(), cartridge, self.model);
+        self.cpu.bus.ppu.set_renderer(renderer);
+        self.cpu.bus.apu.set_host_output(host_output);
+        self.frame_cycles = 0;
+        self.check_rumble();
+    }
+    pub fn model(&self) -> Model::Model
+    {
+        self.model
+    }
+    pub fn set_renderer(&mut self, renderer: PPU::Renderer)
+    {
+        self.cpu.bus.ppu.set_renderer(renderer);
+    }
+    pub fn cartridge(&self) -> &Cartridge::Cartridge
+    {
+        &self.cpu.bus.cartridge
+    }
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge::Cartridge
+    {
+        &mut self.cpu.bus.cartridge
+    }
+    //Returns how many T-cycles the instruction took
+    pub fn step_instruction(&mut self) -> u8
+    {
+        let cycles = self.cpu.step();
+        //Kept within a frame so stepping for a long time doesn't leave run_frame with frames to catch up on
+        self.frame_cycles = (self.frame_cycles + cycles as u32) % CYCLES_PER_FRAME;
+        self.check_rumble();
+        cycles
+    }
+    pub fn run_frame(&mut self)
+    {
+        while self.frame_cycles < CYCLES_PER_FRAME
+        {
+            self.frame_cycles += self.cpu.step() as u32;
+            self.check_rumble();
+        }
+        self.frame_cycles -= CYCLES_PER_FRAME;
+    }
+    fn check_rumble(&mut self)
+    {
+        let rumble = self.cpu.bus.cartridge.mbc.rumble();
+        if rumble != self.rumble
+        {
+            self.rumble = rumble;
+            if let Some(callback) = &mut self.rumble_callback
+            {
+                callback(rumble);
+            }
+        }
+    }
+    pub fn rumble(&self) -> bool
+    {
+        self.cpu.bus.cartridge.mbc.rumble()
+    }
+    pub fn set_rumble_callback(&mut self, callback: impl FnMut(bool) + 'static)
+    {
+        self.rumble_callback = Some(Box::new(callback));
+    }
+    //160x144 pixels in 0RGB
+    pub fn framebuffer(&self) -> &[u32]
+    {
+        self.cpu.bus.ppu.frame_buffer()
+    }
+    //Start producing audio at the host's sample rate (None stops it). Frames build up as the emulator runs
+    //and should be read out every video frame.
+    pub fn set_sample_rate(&mut self, rate: Option<u32>)
+    {
+        self.cpu.bus.apu.set_sample_rate(rate);
+    }
+    //Stereo frames produced since the last read, appended to out. Nothing when no sample rate is set.
+    pub fn read_samples_f32(&mut self, out: &mut Vec<[f32; 2]>)
+    {
+        if let Some(resampler) = self.cpu.bus.apu.resampler_mut()
+        {
+            resampler.read_f32(out);
+        }
+    }
+    pub fn read_samples_i16(&mut self, out: &mut Vec<[i16; 2]>)
+    {
+        if let Some(resampler) = self.cpu.bus.apu.resampler_mut()
+        {
+            resampler.read_i16(out);
+        }
+    }
+    //Also resample every channel on its own, with its panning and master volume applied, so the four stems
+    //add back up to the mix (as it is with nothing muted)
+    pub fn set_audio_stems(&mut self, stems: bool)
+    {
+        self.cpu.bus.apu.set_stems(stems);
+    }
+    pub fn read_stem_i16(&mut self, channel: APU::Channel, out: &mut Vec<[i16; 2]>)
+    {
+        if let Some(resampler) = self.cpu.bus.apu.stem_resampler_mut(channel)
+        {
+            resampler.read_i16(out);
+        }
+    }
+    //Tell the resampler how many frames the host audio queue holds against how many it's aiming for, so it
+    //can speed up or slow down a touch to keep the queue from running dry
+    pub fn adjust_audio_rate(&mut self, buffered: usize, target: usize)
+    {
+        if let Some(resampler) = self.cpu.bus.apu.resampler_mut()
+        {
+            resampler.adjust_rate(buffered, target);
+        }
+    }
+    pub fn set_buttons(&mut self, buttons: Buttons)
+    {
+        self.cpu.bus.set_buttons(buttons);
+    }
+    pub fn apu(&self) -> &APU::APU
+    {
+        &self.cpu.bus.apu
+    }
+    //Muting, soloing and the oscilloscope live here
+    pub fn apu_mut(&mut self) -> &mut APU::APU
+    {
+        &mut self.cpu.bus.apu
+    }
+    pub fn cpu(&self) -> &CPU::CPU
+    {
+        &self.cpu
+    }
+    pub fn cpu_mut(&mut self) -> &mut CPU::CPU
+    {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use Cartridge::test_rom;
+
+    //A cartridge that loops on NOP, NOP, JR -4, 20 cycles a time round so frames end partway through an instruction
+    fn looping_rom() -> Vec<u8>
+    {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0x00, 0x18, 0xFC]);
+        rom
+    }
+
+    #[test]
+    fn run_frame_runs_one_frame_of_cycles()
+    {
+        let mut gameboy = GameBoy::new(None, looping_rom(), Model::Model::DMG).unwrap();
+        let start = gameboy.cpu.bus.timer.counter;
+        for frame in 1..=3
+        {
+            gameboy.run_frame();
+            //Whatever the last instruction ran past the end of the frame is taken off the next one
+            let cycles = gameboy.cpu.bus.timer.counter.wrapping_sub(start) as u32;
+            assert_eq!(cycles, (frame * CYCLES_PER_FRAME + gameboy.frame_cycles) % 0x10000);
+            assert!(gameboy.frame_cycles < 20);
+        }
+        assert_ne!(gameboy.frame_cycles, 0);
+    }
+
+    #[test]
+    fn reset_restores_post_boot_state()
+    {
+        let mut gameboy = GameBoy::new(None, looping_rom(), Model::Model::DMG).unwrap();
+        let fresh = GameBoy::new(None, looping_rom(), Model::Model::DMG).unwrap();
+        gameboy.run_frame();
+        gameboy.step_instruction();
+        gameboy.cpu.registers.a = 0x42;
+        gameboy.cpu.bus.write_byte(0xC000, 0x42);
+        gameboy.reset();
+        let (registers, expected) = (&gameboy.cpu.registers, &fresh.cpu.registers);
+        assert_eq!([registers.a, registers.b, registers.c, registers.d, registers.e, registers.h, registers.l], [expected.a, expected.b, expected.c, expected.d, expected.e, expected.h, expected.l]);
+        assert_eq!((gameboy.cpu.pc, gameboy.cpu.sp), (0x0100, 0xFFFE));
+        assert_eq!(gameboy.cpu.bus.timer.counter, fresh.cpu.bus.timer.counter);
+        assert_eq!(gameboy.cpu.bus.read_byte(0xC000), 0x00);
+        assert_eq!(gameboy.cpu.bus.read_byte(PPU::LY as u16), 0);
+        assert_eq!(gameboy.frame_cycles, 0);
+    }
+
+    #[test]
+    fn load_rom_keeps_the_current_game_on_error()
+    {
+        let mut gameboy = GameBoy::new(None, looping_rom(), Model::Model::DMG).unwrap();
+        assert!(matches!(gameboy.load_rom(vec![0; 0x100]), Err(GameBoyError::Cartridge(CartridgeError::TooSmall(0x100)))));
+        assert_eq!(gameboy.cartridge().header.title, "TEST");
+        assert!(matches!(GameBoy::new(Some(vec![0; 0x80]), looping_rom(), Model::Model::DMG), Err(GameBoyError::BootRomSize(0x80))));
+    }
+}
@@ -1,31 +1,499 @@
-pub mod CPU;
-pub mod Memory;
-pub mod PPU;
-pub mod InterruptFlags;
-pub mod Joypad;
-pub mod Timer;
+#![allow(non_snake_case)]
+
+mod AudioSink;
+mod Oscilloscope;
 
 use std::env::args;
 use std::fs::File;
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+
+use GB_Emulator::{GameBoy, GameBoyError, CPU_CLOCK_HZ, CYCLES_PER_FRAME};
+use GB_Emulator::APU::Channel;
+use GB_Emulator::Cartridge::Cartridge;
+use GB_Emulator::GBS::{Gbs, GbsPlayer};
+use GB_Emulator::Joypad::Buttons;
+use GB_Emulator::Model::Model;
+use GB_Emulator::PPU::{Renderer, SCREEN_HEIGHT, SCREEN_WIDTH};
+use GB_Emulator::RTC::ClockSource;
+use GB_Emulator::WAV::AudioRecorder;
+
+//~59.73 Hz, the DMG doesn't quite manage 60
+const FRAME_DURATION: Duration = Duration::from_nanos(1_000_000_000 * CYCLES_PER_FRAME as u64 / CPU_CLOCK_HZ as u64);
+//How often (in frames) to check whether the game has finished writing to save RAM
+const SAVE_INTERVAL: u32 = 60;
+const AUDIO_SAMPLE_RATE: u32 = 44100;
+//GBS files don't say how long anything is, headless recordings need to stop somewhere
+const DEFAULT_GBS_TRACK_LENGTH: Duration = Duration::from_secs(120);
 
 fn load_rom(filename: &str) -> Vec<u8>
     {
-        let mut file = File::open(filename).expect("FAILED TO OPEN BOOT ROM");
+        let mut file = File::open(filename).expect("FAILED TO OPEN ROM");
         let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).expect("ERROR READING BOOT ROM");
+        file.read_to_end(&mut buffer).expect("ERROR READING ROM");
         buffer
     }
-fn main() 
+fn write_save(path: &Path, data: &[u8])
+    {
+        if let Err(error) = std::fs::write(path, data)
+        {
+            eprintln!("{}: {}", path.display(), error);
+        }
+    }
+//--track-length takes either seconds for every track, or <track>=<seconds> for one (1 based)
+fn parse_track_length(arg: &str) -> Option<(Option<u8>, Duration)>
+    {
+        match arg.split_once('=')
+        {
+            Some((track, seconds)) => Some((Some(track.parse().ok().filter(|&track: &u8| track > 0)?), Duration::from_secs_f64(seconds.parse().ok()?))),
+            None => Some((None, Duration::from_secs_f64(arg.parse().ok()?))),
+        }
+    }
+//1-4 mute or unmute a channel, with Shift they solo it instead
+fn handle_channel_keys(window: &Window, gameboy: &mut GameBoy)
+    {
+        let shift = window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift);
+        let apu = gameboy.apu_mut();
+        for (key, channel) in [Key::Key1, Key::Key2, Key::Key3, Key::Key4].into_iter().zip(Channel::ALL)
+        {
+            if window.is_key_pressed(key, KeyRepeat::No)
+            {
+                if shift
+                {
+                    apu.set_soloed(channel, !apu.soloed(channel));
+                }
+                else
+                {
+                    apu.set_muted(channel, !apu.muted(channel));
+                }
+            }
+        }
+    }
+fn open_oscilloscope(gameboy: &mut GameBoy, title: &str) -> (Window, Oscilloscope::Oscilloscope)
+    {
+        let mut window = Window::new(title, Oscilloscope::WIDTH, Oscilloscope::HEIGHT, WindowOptions { scale: Scale::X2, ..WindowOptions::default() })
+            .expect("FAILED TO OPEN WINDOW");
+        window.set_target_fps(0);
+        gameboy.apu_mut().set_scope(true);
+        (window, Oscilloscope::Oscilloscope::new())
+    }
+//Redraw the oscilloscope, if it's open, and close it down when its window is
+fn update_oscilloscope(scope: &mut Option<(Window, Oscilloscope::Oscilloscope)>, gameboy: &mut GameBoy)
+    {
+        let Some((window, oscilloscope)) = scope else { return };
+        if !window.is_open()
+        {
+            gameboy.apu_mut().set_scope(false);
+            *scope = None;
+            return;
+        }
+        handle_channel_keys(window, gameboy);
+        oscilloscope.update(gameboy.apu_mut());
+        window.update_with_buffer(oscilloscope.buffer(), Oscilloscope::WIDTH, Oscilloscope::HEIGHT)
+            .expect("FAILED TO DRAW OSCILLOSCOPE");
+    }
+//Where each frame's audio goes: the host's sound device, a recording, both or neither. With neither the APU
+//isn't resampled at all.
+struct AudioOutput
+{
+    sink: Option<AudioSink::AudioSink>,
+    recorder: Option<AudioRecorder>,
+    frames: Vec<[f32; 2]>,
+}
+impl AudioOutput
+{
+    //Stems go next to the mix as <file>.pulse1.wav, <file>.pulse2.wav... A recording that can't be created
+    //exits, a sound device that can't be opened just means no sound.
+    fn new(gameboy: &mut GameBoy, play: bool, record: Option<&String>, stems: bool) -> AudioOutput
+    {
+        let sink = play.then(|| AudioSink::AudioSink::open()
+            .inspect_err(|error| eprintln!("No sound: {}", error))
+            .ok()).flatten();
+        //Recording shares the resampler with the sound device, so it has to go at the device's rate
+        let sample_rate = sink.as_ref().map_or(AUDIO_SAMPLE_RATE, |sink| sink.sample_rate());
+        gameboy.set_sample_rate(sink.as_ref().map(|_| sample_rate));
+        let recorder = record.map(|path| match AudioRecorder::create(gameboy, Path::new(path), sample_rate, stems)
+        {
+            Ok(recorder) => recorder,
+            Err(error) =>
+            {
+                eprintln!("{}: {}", path, error);
+                std::process::exit(1);
+            }
+        });
+        AudioOutput { sink, recorder, frames: Vec::new() }
+    }
+    fn recording(&self) -> bool
+    {
+        self.recorder.is_some()
+    }
+    //Move the audio from the frame just run out of the GameBoy. If writing the recording fails it's finished off
+    //with what it has so far, and the resampling that was only there to feed it stops.
+    fn update(&mut self, gameboy: &mut GameBoy)
+    {
+        self.frames.clear();
+        gameboy.read_samples_f32(&mut self.frames);
+        if let Some(sink) = &mut self.sink
+        {
+            sink.play(gameboy, &self.frames);
+        }
+        let Some(recorder) = &mut self.recorder else { return };
+        if let Err(error) = recorder.record(gameboy, &self.frames)
+        {
+            eprintln!("Stopped recording audio: {}", error);
+            if let Some(Err(error)) = self.recorder.take().map(|recorder| recorder.finish())
+            {
+                eprintln!("Failed to finish audio recording: {}", error);
+            }
+            gameboy.set_audio_stems(false);
+            if self.sink.is_none()
+            {
+                gameboy.set_sample_rate(None);
+            }
+        }
+    }
+    fn finish(self)
+    {
+        if let Some(Err(error)) = self.recorder.map(|recorder| recorder.finish())
+        {
+            eprintln!("Failed to finish audio recording: {}", error);
+        }
+    }
+}
+fn gbs_title(player: &GbsPlayer) -> String
+    {
+        format!("GB_Emulator - {} - {}/{}", player.gbs().title, player.track() + 1, player.gbs().song_count)
+    }
+//Plays a GBS file, either in a window with Left/Right to change track or headless straight to a .wav.
+//A GBS driver never turns the LCD on, so the window shows the oscilloscope instead.
+//Headless runs to the end of the last track, or just the one picked with --track.
+fn play_gbs(mut player: GbsPlayer, mut audio: AudioOutput, single_track: bool, headless: bool)
+    {
+        if headless
+        {
+            loop
+            {
+                let finished = player.run_frame();
+                audio.update(player.gameboy_mut());
+                if !audio.recording()
+                {
+                    return;
+                }
+                if finished
+                {
+                    if single_track || player.track() + 1 == player.gbs().song_count
+                    {
+                        break;
+                    }
+                    player.next_track();
+                }
+            }
+            audio.finish();
+            return;
+        }
+
+        let title = gbs_title(&player);
+        let (mut window, mut oscilloscope) = open_oscilloscope(player.gameboy_mut(), &title);
+        let mut next_frame = Instant::now() + FRAME_DURATION;
+        while window.is_open() && !window.is_key_down(Key::Escape)
+        {
+            handle_channel_keys(&window, player.gameboy_mut());
+            let finished = player.run_frame();
+            audio.update(player.gameboy_mut());
+            oscilloscope.update(player.gameboy_mut().apu_mut());
+            window.update_with_buffer(oscilloscope.buffer(), Oscilloscope::WIDTH, Oscilloscope::HEIGHT)
+                .expect("FAILED TO DRAW OSCILLOSCOPE");
+            let track = player.track();
+            if finished || window.is_key_pressed(Key::Right, KeyRepeat::No)
+            {
+                player.next_track();
+            }
+            else if window.is_key_pressed(Key::Left, KeyRepeat::No)
+            {
+                player.previous_track();
+            }
+            if player.track() != track
+            {
+                window.set_title(&gbs_title(&player));
+            }
+
+            let now = Instant::now();
+            if next_frame > now
+            {
+                std::thread::sleep(next_frame - now);
+                next_frame += FRAME_DURATION;
+            }
+            else
+            {
+                next_frame = now + FRAME_DURATION;
+            }
+        }
+        audio.finish();
+    }
+fn read_buttons(window: &Window) -> Buttons
+    {
+        Buttons
+        {
+            a: window.is_key_down(Key::X),
+            b: window.is_key_down(Key::Z),
+            select: window.is_key_down(Key::Backspace),
+            start: window.is_key_down(Key::Enter),
+            right: window.is_key_down(Key::Right),
+            left: window.is_key_down(Key::Left),
+            up: window.is_key_down(Key::Up),
+            down: window.is_key_down(Key::Down),
+        }
+    }
+fn main()
     {
         let args: Vec<String> = args().collect();
-        if args.len() < 2 
+        let usage = format!("Usage: {} [--info] [--rtc-host] [--boot-rom <file>] [--model dmg0|dmg|mgb|sgb|sgb2] [--renderer scanline|fifo] [--record-audio <file.wav> [--record-stems]] [--oscilloscope] [--headless] [--track <n>] [--track-length [<n>=]<seconds>] <game_rom_or_gbs_file>", args[0]);
+        let mut info = false;
+        let mut rtc_host = false;
+        let mut boot_rom_filename = None;
+        let mut model = Model::DMG;
+        let mut renderer = Renderer::Scanline;
+        let mut record_audio = None;
+        let mut record_stems = false;
+        let mut headless = false;
+        let mut oscilloscope = false;
+        let mut track = None;
+        let mut track_lengths = Vec::new();
+        let mut game_rom_filename = None;
+        let mut arguments = args[1..].iter();
+        while let Some(arg) = arguments.next()
+        {
+            match arg.as_str()
+            {
+                "--info" => info = true,
+                "--rtc-host" => rtc_host = true,
+                "--boot-rom" => match arguments.next()
+                {
+                    Some(filename) => boot_rom_filename = Some(filename),
+                    None =>
+                    {
+                        eprintln!("{}", usage);
+                        std::process::exit(1);
+                    }
+                },
+                "--model" => match arguments.next().and_then(|name| Model::from_name(name))
+                {
+                    Some(selected) => model = selected,
+                    None =>
+                    {
+                        eprintln!("{}", usage);
+                        std::process::exit(1);
+                    }
+                },
+                "--renderer" => match arguments.next().map(|name| name.as_str())
+                {
+                    Some("scanline") => renderer = Renderer::Scanline,
+                    Some("fifo") => renderer = Renderer::PixelFifo,
+                    _ =>
+                    {
+                        eprintln!("{}", usage);
+                        std::process::exit(1);
+                    }
+                },
+                "--record-audio" => match arguments.next()
+                {
+                    Some(filename) => record_audio = Some(filename),
+                    None =>
+                    {
+                        eprintln!("{}", usage);
+                        std::process::exit(1);
+                    }
+                },
+                "--record-stems" => record_stems = true,
+                "--headless" => headless = true,
+                "--oscilloscope" => oscilloscope = true,
+                "--track" => match arguments.next().and_then(|track| track.parse::<u8>().ok())
+                {
+                    Some(selected) if selected > 0 => track = Some(selected - 1),
+                    _ =>
+                    {
+                        eprintln!("{}", usage);
+                        std::process::exit(1);
+                    }
+                },
+                "--track-length" => match arguments.next().and_then(|length| parse_track_length(length))
+                {
+                    Some(length) => track_lengths.push(length),
+                    None =>
+                    {
+                        eprintln!("{}", usage);
+                        std::process::exit(1);
+                    }
+                },
+                _ => game_rom_filename = Some(arg),
+            }
+        }
+        let Some(game_rom_filename) = game_rom_filename else
+        {
+            eprintln!("{}", usage);
+            std::process::exit(1);
+        };
+        if record_stems && record_audio.is_none()
         {
-            eprintln!("Usage: {} <boot_rom_file>", args[0]);
+            eprintln!("{}", usage);
             std::process::exit(1);
         }
-        let game_rom_filename = &args[1];
-        let boot_rom = load_rom("boot.bin");
-        let game_rom = load_rom(&game_rom_filename);
-        let _cpu = CPU::CPU::new(boot_rom, game_rom);
+        let game_rom = load_rom(game_rom_filename);
+        if Gbs::is_gbs(&game_rom)
+        {
+            let gbs = match Gbs::parse(&game_rom)
+            {
+                Ok(gbs) => gbs,
+                Err(error) =>
+                {
+                    eprintln!("{}: {}", game_rom_filename, error);
+                    std::process::exit(1);
+                }
+            };
+            if info
+            {
+                println!("{}", gbs);
+                return;
+            }
+            //Headless only makes sense when there's somewhere for the audio to go
+            if headless && record_audio.is_none()
+            {
+                eprintln!("{}", usage);
+                std::process::exit(1);
+            }
+            //Tracks are only known to be out of range once the file's been read
+            let song_count = gbs.song_count;
+            if track.is_some_and(|track| track >= song_count) || track_lengths.iter().any(|&(track, _)| track.is_some_and(|track| track > song_count))
+            {
+                eprintln!("{}", usage);
+                std::process::exit(1);
+            }
+            let mut player = GbsPlayer::new(gbs, model);
+            if headless
+            {
+                player.set_default_length(Some(DEFAULT_GBS_TRACK_LENGTH));
+            }
+            for (track, length) in track_lengths
+            {
+                match track
+                {
+                    Some(track) => player.set_track_length(track - 1, Some(length)),
+                    None => player.set_default_length(Some(length)),
+                }
+            }
+            if let Some(track) = track
+            {
+                player.start_track(track);
+            }
+            let audio = AudioOutput::new(player.gameboy_mut(), !headless, record_audio, record_stems);
+            play_gbs(player, audio, track.is_some(), headless);
+            return;
+        }
+        if headless
+        {
+            eprintln!("{}", usage);
+            std::process::exit(1);
+        }
+        if info
+        {
+            match Cartridge::from_bytes(game_rom)
+            {
+                Ok(cartridge) => println!("{}", cartridge),
+                Err(error) =>
+                {
+                    eprintln!("{}: {}", game_rom_filename, error);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        //Without a boot ROM we start at the cartridge with everything set up as the model's boot ROM would leave it
+        let boot_rom = boot_rom_filename.map(|filename| load_rom(filename));
+        let mut gameboy = match GameBoy::new(boot_rom, game_rom, model)
+        {
+            Ok(gameboy) => gameboy,
+            Err(error @ GameBoyError::BootRomSize(_)) =>
+            {
+                eprintln!("{}: {}", boot_rom_filename.unwrap(), error);
+                std::process::exit(1);
+            }
+            Err(error) =>
+            {
+                eprintln!("{}: {}", game_rom_filename, error);
+                std::process::exit(1);
+            }
+        };
+        if !gameboy.cartridge().header_checksum_valid()
+        {
+            eprintln!("{}: warning: header checksum doesn't match, a real Game Boy wouldn't boot this", game_rom_filename);
+        }
+        gameboy.set_renderer(renderer);
+        //MBC3 clocks follow emulated time unless asked to keep up with the real one
+        if let Some(rtc) = gameboy.cartridge_mut().rtc_mut()
+        {
+            if rtc_host
+            {
+                rtc.source = ClockSource::Host;
+            }
+        }
+        //Battery backed RAM lives next to the ROM as <rom>.sav
+        let save_path = gameboy.cartridge().has_battery().then(|| PathBuf::from(game_rom_filename).with_extension("sav"));
+        if let Some(save_path) = &save_path
+        {
+            if let Ok(data) = std::fs::read(save_path)
+            {
+                gameboy.cartridge_mut().load_save_data(&data);
+            }
+        }
+
+        let mut audio = AudioOutput::new(&mut gameboy, true, record_audio, record_stems);
+
+        let mut window = Window::new("GB_Emulator", SCREEN_WIDTH, SCREEN_HEIGHT, WindowOptions { scale: Scale::X4, ..WindowOptions::default() })
+            .expect("FAILED TO OPEN WINDOW");
+        //We do our own pacing below, minifb's limiter only takes whole frames per second
+        window.set_target_fps(0);
+        let mut scope = oscilloscope.then(|| open_oscilloscope(&mut gameboy, "GB_Emulator - Oscilloscope"));
+
+        let mut frames_since_save = 0;
+        let mut next_frame = Instant::now() + FRAME_DURATION;
+        while window.is_open() && !window.is_key_down(Key::Escape)
+        {
+            gameboy.set_buttons(read_buttons(&window));
+            handle_channel_keys(&window, &mut gameboy);
+            gameboy.run_frame();
+            window.update_with_buffer(gameboy.framebuffer(), SCREEN_WIDTH, SCREEN_HEIGHT)
+                .expect("FAILED TO DRAW FRAME");
+            update_oscilloscope(&mut scope, &mut gameboy);
+            audio.update(&mut gameboy);
+
+            frames_since_save += 1;
+            if frames_since_save >= SAVE_INTERVAL
+            {
+                frames_since_save = 0;
+                if let (Some(save_path), Some(data)) = (&save_path, gameboy.cartridge_mut().flush())
+                {
+                    write_save(save_path, &data);
+                }
+            }
+
+            let now = Instant::now();
+            if next_frame > now
+            {
+                std::thread::sleep(next_frame - now);
+                next_frame += FRAME_DURATION;
+            }
+            else
+            {
+                //Running behind, don't try to catch up with a burst of frames
+                next_frame = now + FRAME_DURATION;
+            }
+        }
+        if let Some(save_path) = &save_path
+        {
+            write_save(save_path, &gameboy.cartridge().save_data());
+        }
+        audio.finish();
     }